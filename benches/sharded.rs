@@ -0,0 +1,114 @@
+// Compares allocation throughput of a single tree allocator behind a
+// mutex with the sharded allocator, as the number of threads grows.
+//
+// cargo bench --bench sharded
+
+use bsp_block_allocator::allocator::Allocator;
+use bsp_block_allocator::sharded::ShardedAllocator;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+//----------------------------------------------------------------
+
+const NR_BLOCKS: u64 = 1 << 22;
+const NR_NODES: u8 = 255;
+const BLOCKS_PER_THREAD: u64 = 1 << 16;
+
+// A bitmap that threads can allocate from without a lock.
+struct SpaceMap {
+    words: Vec<AtomicU64>,
+}
+
+impl SpaceMap {
+    fn new(nr_blocks: u64) -> Self {
+        Self {
+            words: (0..nr_blocks.div_ceil(64))
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+        }
+    }
+
+    fn alloc(&self, begin: u64, end: u64) -> io::Result<Option<u64>> {
+        let mut b = begin;
+        while b < end {
+            let word = &self.words[(b / 64) as usize];
+            let bit = 1 << (b % 64);
+            if word.fetch_or(bit, Ordering::Relaxed) & bit == 0 {
+                return Ok(Some(b));
+            }
+            b += 1;
+        }
+        Ok(None)
+    }
+}
+
+fn run_single(nr_threads: usize) -> Duration {
+    let allocator = Mutex::new(Allocator::new(NR_BLOCKS, NR_NODES));
+    let sm = SpaceMap::new(NR_BLOCKS);
+
+    let start = Instant::now();
+    thread::scope(|s| {
+        for _ in 0..nr_threads {
+            s.spawn(|| {
+                let context = allocator.lock().unwrap().get_context();
+                for _ in 0..BLOCKS_PER_THREAD {
+                    let mut allocator = allocator.lock().unwrap();
+                    allocator
+                        .alloc(&context, |b, e| sm.alloc(b, e))
+                        .unwrap()
+                        .unwrap();
+                }
+                allocator.lock().unwrap().put_context(context);
+            });
+        }
+    });
+    start.elapsed()
+}
+
+fn run_sharded(nr_threads: usize) -> Duration {
+    let allocator = ShardedAllocator::new(NR_BLOCKS, nr_threads, NR_NODES);
+    let sm = SpaceMap::new(NR_BLOCKS);
+
+    let start = Instant::now();
+    thread::scope(|s| {
+        for _ in 0..nr_threads {
+            s.spawn(|| {
+                let mut context = allocator.get_context();
+                for _ in 0..BLOCKS_PER_THREAD {
+                    allocator
+                        .alloc(&mut context, |b, e| sm.alloc(b, e))
+                        .unwrap()
+                        .unwrap();
+                }
+                allocator.put_context(context);
+            });
+        }
+    });
+    start.elapsed()
+}
+
+fn blocks_per_sec(nr_threads: usize, elapsed: Duration) -> f64 {
+    (nr_threads as u64 * BLOCKS_PER_THREAD) as f64 / elapsed.as_secs_f64()
+}
+
+fn main() {
+    println!(
+        "{:>8} {:>16} {:>16}",
+        "threads", "single (blk/s)", "sharded (blk/s)"
+    );
+    for nr_threads in [1, 2, 4, 8, 16] {
+        let single = run_single(nr_threads);
+        let sharded = run_sharded(nr_threads);
+        println!(
+            "{:>8} {:>16.0} {:>16.0}",
+            nr_threads,
+            blocks_per_sec(nr_threads, single),
+            blocks_per_sec(nr_threads, sharded)
+        );
+    }
+}
+
+//----------------------------------------------------------------
@@ -1,101 +1,989 @@
+use crate::discard::DiscardTracker;
+use crate::journal::{replay, Journal};
+use crate::range_set::RangeSet;
+use crate::space_map::SpaceMap;
 use crate::tree::*;
 
 use std::collections::BTreeMap;
 use std::io;
-use std::sync::{Arc, Mutex, Weak};
+use std::ops::Range;
+#[cfg(debug_assertions)]
+use std::panic::Location;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
 
 #[cfg(test)]
 mod tests;
 
 //----------------------------------------------------------------
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RevokeReason {
+    Full,
+    Reset,
+    Resize,
+    Preempted,
+    Rescan,
+    Rollback,
+    BadBlocks,
+}
+
+// Passed to a context's revoke handler when the allocator takes its
+// extent away.  begin..end is the range the extent covered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Revocation {
+    pub reason: RevokeReason,
+    pub begin: u64,
+    pub end: u64,
+}
+
+type RevokeHandler = Box<dyn FnMut(&Revocation) + Send>;
+
+pub type TxnId = u64;
+
 pub struct AllocContext {
     extent: Option<Arc<Mutex<Extent>>>,
     next: Option<Arc<Mutex<Self>>>,
     prev: Option<Weak<Mutex<Self>>>,
+    on_revoke: Option<RevokeHandler>,
+    class: ClassId,
+    priority: Priority,
+
+    // Blocks allocated are provisional, until this txn commits.
+    txn: Option<TxnId>,
+
+    // stats
+    nr_allocated: u64,
+    nr_extents: u64,
 }
 
 impl AllocContext {
-    fn new() -> Self {
+    fn new(class: ClassId, priority: Priority) -> Self {
         Self {
             extent: None,
             prev: None,
             next: None,
+            on_revoke: None,
+            class,
+            priority,
+            txn: None,
+            nr_allocated: 0,
+            nr_extents: 0,
+        }
+    }
+
+    // The range covered by the extent currently held, if any.
+    pub fn extent_range(&self) -> Option<Range<u64>> {
+        self.extent.as_ref().map(|extent| {
+            let extent = extent.lock().unwrap();
+            extent.begin..extent.end
+        })
+    }
+
+    pub fn cursor(&self) -> Option<u64> {
+        self.extent
+            .as_ref()
+            .map(|extent| extent.lock().unwrap().cursor)
+    }
+
+    pub fn nr_allocated(&self) -> u64 {
+        self.nr_allocated
+    }
+
+    // The number of times this context has had to move to a new extent.
+    pub fn nr_extent_switches(&self) -> u64 {
+        self.nr_extents.saturating_sub(1)
+    }
+
+    // Tags subsequent allocations with a transaction.  They're freed if
+    // it's aborted.
+    pub fn set_txn(&mut self, txn: Option<TxnId>) {
+        self.txn = txn;
+    }
+
+    pub fn txn(&self) -> Option<TxnId> {
+        self.txn
+    }
+
+    // Registers a handler that's called whenever the allocator revokes
+    // this context's extent.  It runs with the context locked, so it
+    // mustn't call back into the allocator with this context.
+    pub fn on_revoke<F>(&mut self, f: F)
+    where
+        F: FnMut(&Revocation) + Send + 'static,
+    {
+        self.on_revoke = Some(Box::new(f));
+    }
+
+    fn revoke(&mut self, reason: RevokeReason) {
+        if let Some(extent) = self.extent.take() {
+            if let Some(f) = self.on_revoke.as_mut() {
+                let extent = extent.lock().unwrap();
+                f(&Revocation {
+                    reason,
+                    begin: extent.begin,
+                    end: extent.end,
+                });
+            }
         }
     }
 }
 
-fn reset_chained_contexts(ac: &mut AllocContext) {
-    ac.extent = None;
+// Returns the number of contexts that were reset.
+// The number of contexts in the chain starting at ac, which must be the
+// head.  Only walks forwards, so takes locks in the same order as
+// everything else.
+fn nr_chained_contexts(ac: &AllocContext) -> usize {
+    let mut next = ac.next.clone();
+    let mut count = 1;
+
+    while let Some(n) = next {
+        next = n.lock().unwrap().next.clone();
+        count += 1;
+    }
+
+    count
+}
+
+fn reset_chained_contexts(ac: &mut AllocContext, reason: RevokeReason) -> usize {
+    ac.revoke(reason);
     ac.prev = None;
     let mut next = ac.next.take();
+    let mut count = 1;
 
     while let Some(n) = next {
         let mut ac = n.lock().unwrap();
-        ac.extent = None;
+        ac.revoke(reason);
         ac.prev = None;
         next = ac.next.take();
+        count += 1;
     }
+
+    count
 }
 
-pub struct Allocator {
+// Contexts whose guards were dropped without being put.  The guard can't
+// reach the allocator, so it queues the context here and the allocator
+// releases it the next time it's used.
+#[derive(Default)]
+struct Deferred {
+    puts: Vec<Arc<Mutex<AllocContext>>>,
+
+    // Where each context that's neither been put nor dropped was got.
+    #[cfg(debug_assertions)]
+    outstanding: BTreeMap<usize, &'static Location<'static>>,
+}
+
+impl Deferred {
+    #[cfg(debug_assertions)]
+    fn returned(&mut self, context: &Arc<Mutex<AllocContext>>) {
+        self.outstanding.remove(&(Arc::as_ptr(context) as usize));
+    }
+}
+
+// Returned by Allocator::get_context().  Hand it back with put_context(),
+// otherwise the context's extent is released when the guard is dropped.
+// The context itself is only reachable through the guard, so nothing
+// can go on using it once it's been released.
+pub struct ContextGuard {
+    context: Option<Arc<Mutex<AllocContext>>>,
+    deferred: Weak<Mutex<Deferred>>,
+}
+
+impl ContextGuard {
+    pub fn lock(&self) -> MutexGuard<'_, AllocContext> {
+        self.inner().lock().unwrap()
+    }
+
+    fn inner(&self) -> &Arc<Mutex<AllocContext>> {
+        self.context.as_ref().unwrap()
+    }
+
+    fn take(mut self) -> Arc<Mutex<AllocContext>> {
+        self.context.take().unwrap()
+    }
+}
+
+impl Drop for ContextGuard {
+    fn drop(&mut self) {
+        let Some(context) = self.context.take() else {
+            return;
+        };
+
+        if let Some(deferred) = self.deferred.upgrade() {
+            let mut deferred = deferred.lock().unwrap();
+
+            #[cfg(debug_assertions)]
+            deferred.returned(&context);
+
+            deferred.puts.push(context);
+        }
+    }
+}
+
+// Blocks set aside by Allocator::reserve().  Allocations made with
+// alloc_reserved() consume it, and whatever is left is handed back
+// when it's dropped.
+pub struct Reservation {
+    remaining: u64,
+    reserved: Arc<AtomicU64>,
+}
+
+impl Reservation {
+    pub fn remaining(&self) -> u64 {
+        self.remaining
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.reserved.fetch_sub(self.remaining, Ordering::SeqCst);
+    }
+}
+
+pub type ClassId = usize;
+
+// An allocation class hands out blocks from its own region of the
+// device.  When the region is full, contexts in the class spill over
+// into spill_to, if set.
+#[derive(Clone, Debug)]
+pub struct ClassConfig {
+    pub begin: u64,
+    pub end: u64,
+    pub nr_nodes: u8,
+    pub emergency_reserve: f64,
+    pub spill_to: Option<ClassId>,
+}
+
+impl ClassConfig {
+    // A class over begin..end with no emergency reserve or spillover.
+    pub fn new(begin: u64, end: u64, nr_nodes: u8) -> Self {
+        Self {
+            begin,
+            end,
+            nr_nodes,
+            emergency_reserve: 0.0,
+            spill_to: None,
+        }
+    }
+}
+
+// A snapshot of the allocator's trees and open transactions, taken by
+// Allocator::checkpoint().
+#[derive(Clone, Debug)]
+pub struct Checkpoint {
+    trees: Vec<TreeCheckpoint>,
+    txns: BTreeMap<TxnId, Vec<(u64, u64)>>,
+    pending_frees: RangeSet,
+}
+
+impl Checkpoint {
+    pub fn nr_holders(&self) -> usize {
+        self.trees.iter().map(|tree| tree.nr_holders()).sum()
+    }
+}
+
+struct Class {
     extents: Tree,
+    spill_to: Option<ClassId>,
+}
+
+pub struct Allocator {
+    classes: Vec<Class>,
     holders: BTreeMap<u64, Arc<Mutex<AllocContext>>>,
+    deferred: Arc<Mutex<Deferred>>,
+
+    // Total blocks held by outstanding reservations.
+    reserved: Arc<AtomicU64>,
+
+    // Rebuild the trees from the space map, rather than report ENOSPC.
+    rescan: bool,
+
+    // The space map's free count when a rescan last came up empty.  It's
+    // not worth trying again until that changes.
+    rescan_failed_at: Option<u64>,
+
+    journal: Option<Journal>,
+
+    // Provisional (begin, len) runs for each open transaction, in
+    // allocation order.
+    txns: BTreeMap<TxnId, Vec<(u64, u64)>>,
+
+    // Blocks freed in the current transaction.  They're kept out of the
+    // tree and space map until commit_frees(), so they can't be reused
+    // before the transaction that freed them is on disk.
+    pending_frees: RangeSet,
+
+    discards: Option<DiscardTracker>,
 }
 
 impl Allocator {
     pub fn new(nr_blocks: u64, nr_nodes: u8) -> Self {
-        // Create a tree that brackets the entire address space
-        let extents = Tree::new(nr_blocks, nr_nodes);
+        // Create a single class that brackets the entire address space
+        Self::with_classes(&[ClassConfig::new(0, nr_blocks, nr_nodes)])
+    }
+
+    // Creates an allocator for a device that's already in use.  The tree
+    // is seeded from the space map so initial extents skip the regions
+    // that are already full.
+    pub fn from_space_map<S: SpaceMap + ?Sized>(sm: &S, nr_nodes: u8) -> Self {
+        let mut allocator = Self::new(sm.nr_blocks(), nr_nodes);
+        for class in &mut allocator.classes {
+            class.extents.rebuild(sm);
+        }
+        allocator
+    }
+
+    pub fn with_classes(configs: &[ClassConfig]) -> Self {
+        assert!(!configs.is_empty());
+
+        let mut classes = Vec::new();
+        for (i, config) in configs.iter().enumerate() {
+            for other in &configs[..i] {
+                assert!(config.end <= other.begin || other.end <= config.begin);
+            }
+            if let Some(spill) = config.spill_to {
+                assert!(spill < configs.len() && spill != i);
+            }
+
+            let mut extents = Tree::new_region(config.begin, config.end, config.nr_nodes);
+            extents.set_emergency_reserve(config.emergency_reserve);
+            classes.push(Class {
+                extents,
+                spill_to: config.spill_to,
+            });
+        }
 
         Allocator {
-            extents,
+            classes,
             holders: BTreeMap::new(),
+            deferred: Arc::new(Mutex::new(Deferred::default())),
+            reserved: Arc::new(AtomicU64::new(0)),
+            rescan: false,
+            rescan_failed_at: None,
+            journal: None,
+            txns: BTreeMap::new(),
+            pending_frees: RangeSet::new(),
+            discards: None,
+        }
+    }
+
+    // The tree that contains the given block.
+    fn tree(&self, block: u64) -> &Tree {
+        &self
+            .classes
+            .iter()
+            .find(|c| c.extents.contains(block))
+            .unwrap()
+            .extents
+    }
+
+    fn tree_mut(&mut self, block: u64) -> &mut Tree {
+        &mut self
+            .classes
+            .iter_mut()
+            .find(|c| c.extents.contains(block))
+            .unwrap()
+            .extents
+    }
+
+    // The number of free blocks available to normal contexts, ie. not
+    // reserved or in the emergency reserve.
+    pub fn nr_free_blocks(&self) -> u64 {
+        (0..self.classes.len())
+            .map(|class| self.nr_free_blocks_in(class))
+            .sum::<u64>()
+            .saturating_sub(self.reserved.load(Ordering::SeqCst))
+    }
+
+    // As nr_free_blocks(), but for a single class and ignoring reservations.
+    pub fn nr_free_blocks_in(&self, class: ClassId) -> u64 {
+        let extents = &self.classes[class].extents;
+        extents
+            .nr_free_blocks()
+            .saturating_sub(extents.nr_emergency_blocks())
+    }
+
+    // Sets aside a fraction of each class's blocks for privileged contexts.
+    pub fn set_emergency_reserve(&mut self, fraction: f64) {
+        for class in &mut self.classes {
+            class.extents.set_emergency_reserve(fraction);
+        }
+    }
+
+    // Sets aside n blocks for later allocation with alloc_reserved().
+    // Returns None if there aren't enough unreserved free blocks.
+    pub fn reserve(&mut self, n: u64) -> Option<Reservation> {
+        self.put_deferred();
+
+        if self.nr_free_blocks() < n {
+            return None; // -ENOSPC
+        }
+
+        self.reserved.fetch_add(n, Ordering::SeqCst);
+        Some(Reservation {
+            remaining: n,
+            reserved: self.reserved.clone(),
+        })
+    }
+
+    // Whether an allocation from the extent starting at extent_begin
+    // would have to eat into reservations, or the emergency reserve.
+    fn may_be_held_back(&self, extent_begin: u64, priority: Priority, reserved: bool) -> bool {
+        (!reserved && self.reserved.load(Ordering::SeqCst) > 0)
+            || (priority == Priority::Normal && self.tree(extent_begin).nr_emergency_blocks() > 0)
+    }
+
+    // Whether allocating len blocks would eat into them.  Counting free
+    // blocks locks every extent, so the caller mustn't be holding one.
+    fn held_back(&self, extent_begin: u64, priority: Priority, reserved: bool, len: u64) -> bool {
+        if !reserved {
+            let total: u64 = self
+                .classes
+                .iter()
+                .map(|c| c.extents.nr_free_blocks())
+                .sum();
+            if total < self.reserved.load(Ordering::SeqCst).saturating_add(len) {
+                return true;
+            }
+        }
+
+        let extents = self.tree(extent_begin);
+        priority == Priority::Normal
+            && extents.nr_free_blocks() < extents.nr_emergency_blocks().saturating_add(len)
+    }
+
+    #[track_caller]
+    pub fn get_context(&mut self) -> ContextGuard {
+        self.get_context_in(0, Priority::Normal)
+    }
+
+    // A context that may allocate from the emergency reserve.
+    #[track_caller]
+    pub fn get_privileged_context(&mut self) -> ContextGuard {
+        self.get_context_in(0, Priority::Privileged)
+    }
+
+    // A context that allocates from the given class.
+    #[track_caller]
+    pub fn get_context_in(&mut self, class: ClassId, priority: Priority) -> ContextGuard {
+        assert!(class < self.classes.len());
+        self.put_deferred();
+
+        let context = Arc::new(Mutex::new(AllocContext::new(class, priority)));
+
+        #[cfg(debug_assertions)]
+        self.deferred
+            .lock()
+            .unwrap()
+            .outstanding
+            .insert(Arc::as_ptr(&context) as usize, Location::caller());
+
+        ContextGuard {
+            context: Some(context),
+            deferred: Arc::downgrade(&self.deferred),
         }
     }
 
-    pub fn get_context(&mut self) -> Arc<Mutex<AllocContext>> {
-        Arc::new(Mutex::new(AllocContext::new()))
+    pub fn put_context(&mut self, context: ContextGuard) {
+        self.put_deferred();
+        let context = context.take();
+
+        #[cfg(debug_assertions)]
+        self.deferred.lock().unwrap().returned(&context);
+
+        self.put_context_(context);
     }
 
-    pub fn put_context(&mut self, context: Arc<Mutex<AllocContext>>) {
+    fn put_context_(&mut self, context: Arc<Mutex<AllocContext>>) {
         let mut ctx = context.lock().unwrap();
+        self.drop_extent(&mut ctx);
+    }
 
+    // Gives up this context's hold on its extent, leaving any co-holders
+    // in place.
+    fn drop_extent(&mut self, ctx: &mut AllocContext) {
         if let Some(extent) = ctx.extent.take() {
             let extent_begin = extent.lock().unwrap().begin;
-            self.remove_holder(extent_begin, &mut ctx);
-            self.extents.release(extent);
+            self.remove_holder(extent_begin, ctx);
+            self.tree_mut(extent_begin).release(extent);
+        }
+    }
+
+    pub fn alloc<F>(&mut self, context: &ContextGuard, f: F) -> io::Result<Option<u64>>
+    where
+        F: FnMut(u64, u64) -> io::Result<Option<u64>>,
+    {
+        let mut block = None;
+        self.alloc_(context.inner().clone(), 1, false, f, |b| block = Some(b))?;
+        Ok(block)
+    }
+
+    // As alloc(), but the block comes out of the reservation.
+    pub fn alloc_reserved<F>(
+        &mut self,
+        context: &ContextGuard,
+        reservation: &mut Reservation,
+        f: F,
+    ) -> io::Result<Option<u64>>
+    where
+        F: FnMut(u64, u64) -> io::Result<Option<u64>>,
+    {
+        assert!(Arc::ptr_eq(&reservation.reserved, &self.reserved));
+        if reservation.remaining == 0 {
+            return Err(io::Error::other("reservation exhausted"));
+        }
+
+        let mut block = None;
+        self.alloc_(context.inner().clone(), 1, true, f, |b| block = Some(b))?;
+        if block.is_some() {
+            reservation.remaining -= 1;
+            self.reserved.fetch_sub(1, Ordering::SeqCst);
         }
+        Ok(block)
     }
 
-    pub fn alloc<F>(
+    // Allocates up to n blocks, appending them to out.  Returns the number
+    // allocated, which is less than n if we ran out of space.
+    pub fn alloc_many<F>(
+        &mut self,
+        context: &ContextGuard,
+        n: u64,
+        out: &mut Vec<u64>,
+        f: F,
+    ) -> io::Result<u64>
+    where
+        F: FnMut(u64, u64) -> io::Result<Option<u64>>,
+    {
+        self.alloc_(context.inner().clone(), n, false, f, |b| out.push(b))
+    }
+
+    // Allocates a block from the space map.  If the trees are exhausted
+    // and rescan mode is on, they're rebuilt from the space map and the
+    // allocation retried, so we only report ENOSPC when there's no free
+    // space the trees could hand out.
+    pub fn alloc_from<S: SpaceMap + ?Sized>(
+        &mut self,
+        context: &ContextGuard,
+        sm: &mut S,
+    ) -> io::Result<Option<u64>> {
+        let b = self.alloc(context, |begin, end| sm.alloc_in(begin, end))?;
+        self.recount(sm);
+        if b.is_some()
+            || !self.rescan
+            || self.rescan_failed_at == Some(sm.nr_free())
+            || self.nr_hidden_free(sm) == 0
+        {
+            return Ok(b);
+        }
+
+        self.rescan(sm);
+        let b = self.alloc(context, |begin, end| sm.alloc_in(begin, end))?;
+        self.recount(sm);
+        self.rescan_failed_at = b.is_none().then(|| sm.nr_free());
+        Ok(b)
+    }
+
+    // Free blocks in the space map that the trees don't know about, eg.
+    // because they were freed behind a cursor.  Blocks that are held back
+    // for reservations or the emergency reserve are already counted by
+    // the trees, and bad or blocked ones are never usable, so neither
+    // makes a rescan worthwhile.
+    fn nr_hidden_free<S: SpaceMap + ?Sized>(&self, sm: &S) -> u64 {
+        self.classes
+            .iter()
+            .map(|class| {
+                let (begin, end) = class.extents.region();
+                sm.count_free(begin, end)
+                    .saturating_sub(class.extents.nr_unusable_free(sm))
+                    .saturating_sub(class.extents.nr_free_blocks())
+            })
+            .sum()
+    }
+
+    // Splitting a leaf has to estimate the free counts of its children,
+    // this corrects them.
+    pub fn recount<S: SpaceMap + ?Sized>(&mut self, sm: &S) {
+        for class in &mut self.classes {
+            class.extents.recount(sm);
+        }
+    }
+
+    // Tells the allocator that block b has been freed, so the free
+    // counts stay accurate.
+    pub fn freed(&mut self, b: u64) {
+        if let Some(class) = self.classes.iter_mut().find(|c| c.extents.contains(b)) {
+            class.extents.freed(b);
+        }
+    }
+
+    // Drops a reference to block b.  The tree only sees it as free once
+    // the space map says nothing else refers to it.  An error means b
+    // hasn't been freed.  A failed discard doesn't count, the blocks stay
+    // pending in the tracker and flush_discards() retries them.
+    pub fn free<S: SpaceMap + ?Sized>(&mut self, sm: &mut S, b: u64) -> io::Result<()> {
+        if sm.dec_ref(b)? {
+            self.freed(b);
+            if let Some(discards) = &mut self.discards {
+                let _ = discards.freed(b, b + 1);
+            }
+        }
+        Ok(())
+    }
+
+    // Starts journalling tree events, beginning with a snapshot of every
+    // class.
+    pub fn set_journal(&mut self, journal: Journal) {
+        for class in &mut self.classes {
+            class.extents.set_journalling(true);
+        }
+        self.journal = Some(journal);
+    }
+
+    // Writes the events since the last sync to the journal.
+    pub fn sync_journal(&mut self) -> io::Result<()> {
+        let Some(journal) = &mut self.journal else {
+            return Ok(());
+        };
+
+        for (i, class) in self.classes.iter_mut().enumerate() {
+            for event in class.extents.take_events() {
+                journal.append(i, &event);
+            }
+        }
+        journal.sync()
+    }
+
+    // Restarts the journal from a snapshot of every class, truncating the
+    // events before it.  Call it after the space map has been persisted,
+    // to stop the journal growing without bound.
+    pub fn checkpoint_journal(&mut self) -> io::Result<()> {
+        let Some(journal) = &mut self.journal else {
+            return Ok(());
+        };
+
+        let mut records = Vec::new();
+        for (i, class) in self.classes.iter_mut().enumerate() {
+            class.extents.set_journalling(true);
+            records.extend(class.extents.take_events().into_iter().map(|e| (i, e)));
+        }
+        journal.rewrite(&records)
+    }
+
+    // Recreates an allocator after a crash, by replaying the journal at
+    // path onto the last snapshot of each class.  Journalling carries on
+    // in the same file.
+    pub fn recover<P, S>(configs: &[ClassConfig], path: P, sm: &S) -> io::Result<Self>
+    where
+        P: AsRef<Path>,
+        S: SpaceMap + ?Sized,
+    {
+        let (journal, records) = Journal::open(path)?;
+        let mut allocator = Self::with_classes(configs);
+        for (i, image) in replay(&records) {
+            if let Some(class) = allocator.classes.get_mut(i) {
+                class.extents.restore(&image, sm)?;
+            }
+        }
+        allocator.set_journal(journal);
+        allocator.sync_journal()?;
+        Ok(allocator)
+    }
+
+    // Bookkeeping for a run of blocks that's just been handed out.
+    fn note_allocated(&mut self, txn: Option<TxnId>, b: u64, len: u64) {
+        if let Some(discards) = &mut self.discards {
+            discards.allocated(b, b + len);
+        }
+        if let Some(txn) = txn {
+            self.txns.entry(txn).or_default().push((b, len));
+        }
+    }
+
+    // The blocks allocated in a transaction that's still open.
+    pub fn provisional_blocks(&self, txn: TxnId) -> Vec<u64> {
+        self.txns
+            .get(&txn)
+            .into_iter()
+            .flatten()
+            .flat_map(|(b, len)| *b..(b + len))
+            .collect()
+    }
+
+    // Makes the transaction's blocks permanent.
+    pub fn commit(&mut self, txn: TxnId) {
+        self.txns.remove(&txn);
+    }
+
+    // Frees the transaction's blocks.  Where nothing has been allocated
+    // after them the extent's cursor is wound back, so they're reused
+    // straight away.  On error, the blocks not yet freed stay with the
+    // transaction, so it can be aborted again.
+    pub fn abort<S: SpaceMap + ?Sized>(&mut self, txn: TxnId, sm: &mut S) -> io::Result<()> {
+        self.put_deferred();
+
+        let runs = self.txns.remove(&txn).unwrap_or_default();
+        for (i, &(b, len)) in runs.iter().enumerate().rev() {
+            if let Some(class) = self.classes.iter_mut().find(|c| c.extents.contains(b)) {
+                class.extents.rewind(b, len);
+            }
+            for block in (b..(b + len)).rev() {
+                if let Err(e) = self.free(sm, block) {
+                    let mut unfreed = runs[..i].to_vec();
+                    unfreed.push((b, block + 1 - b));
+                    self.txns.insert(txn, unfreed);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Frees begin..end once the current transaction commits.
+    pub fn defer_free(&mut self, begin: u64, end: u64) {
+        assert!(!self.pending_frees.intersects(begin, end));
+        self.pending_frees.insert(begin, end);
+    }
+
+    pub fn nr_pending_frees(&self) -> u64 {
+        self.pending_frees.nr_blocks()
+    }
+
+    // Called once the transaction has committed, to hand the deferred
+    // frees back to the space map and tree.  It's the other half of
+    // commit(txn), which keeps the blocks the transaction allocated;
+    // call both once the transaction is on disk.  Returns how many blocks
+    // were freed.  On error, the blocks not yet freed stay pending.
+    pub fn commit_frees<S: SpaceMap + ?Sized>(&mut self, sm: &mut S) -> io::Result<u64> {
+        let pending = std::mem::take(&mut self.pending_frees);
+        for (begin, end) in pending.iter() {
+            for b in begin..end {
+                if let Err(e) = self.free(sm, b) {
+                    for (begin, end) in pending.iter() {
+                        self.pending_frees.insert(begin.max(b), end);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok(pending.nr_blocks())
+    }
+
+    // Discards blocks as they're freed through free().
+    pub fn set_discard_tracker(&mut self, tracker: DiscardTracker) {
+        self.discards = Some(tracker);
+    }
+
+    // Discards any complete granules that are still pending, including
+    // any that failed to be discarded as they were freed.
+    pub fn flush_discards(&mut self) -> io::Result<()> {
+        match &mut self.discards {
+            Some(discards) => discards.flush(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn set_rescan(&mut self, enabled: bool) {
+        self.rescan = enabled;
+    }
+
+    // Revokes all extents and rebuilds the trees from the space map.
+    pub fn rescan<S: SpaceMap + ?Sized>(&mut self, sm: &S) {
+        self.put_deferred();
+        self.reset_all_contexts(RevokeReason::Rescan);
+        for class in &mut self.classes {
+            class.extents.rebuild(sm);
+        }
+    }
+
+    // Borrows a new extent for the context, spilling over into other
+    // classes if need be.  Returns false if there's no space left.
+    fn borrow_extent(
+        &mut self,
+        context: &Arc<Mutex<AllocContext>>,
+        ctx: &mut AllocContext,
+        exclude: &[u64],
+    ) -> bool {
+        let mut class = Some(ctx.class);
+        for _ in 0..self.classes.len() {
+            let Some(c) = class else {
+                break;
+            };
+
+            ctx.extent = self.classes[c].extents.borrow_with(ctx.priority, exclude);
+            if ctx.extent.is_some() {
+                break;
+            }
+            class = self.classes[c].spill_to;
+        }
+
+        if ctx.extent.is_none() {
+            return false;
+        }
+
+        let extent_begin = ctx.extent.as_ref().unwrap().lock().unwrap().begin;
+        self.add_holder(extent_begin, context, ctx);
+        ctx.nr_extents += 1;
+        true
+    }
+
+    fn alloc_<F, G>(
         &mut self,
         context: Arc<Mutex<AllocContext>>,
+        n: u64,
+        reserved: bool,
+        mut f: F,
+        mut emit: G,
+    ) -> io::Result<u64>
+    where
+        F: FnMut(u64, u64) -> io::Result<Option<u64>>,
+        G: FnMut(u64),
+    {
+        self.put_deferred();
+
+        let mut count = 0;
+        while count < n {
+            let mut ctx = context.lock().unwrap();
+
+            if ctx.extent.is_none() && !self.borrow_extent(&context, &mut ctx, &[]) {
+                break; // -ENOSPC
+            }
+
+            let extent_ref = ctx.extent.clone().unwrap();
+            let mut extent = extent_ref.lock().unwrap();
+
+            // Keep allocating from this extent until it's used up.
+            while count < n {
+                let extent_begin = extent.begin;
+                if self.may_be_held_back(extent_begin, ctx.priority, reserved) {
+                    drop(extent);
+                    if self.held_back(extent_begin, ctx.priority, reserved, 1) {
+                        return Ok(count); // -ENOSPC
+                    }
+                    extent = extent_ref.lock().unwrap();
+                }
+
+                // Only offer the part of the extent that isn't blocked.
+                let Some((begin, end)) = self
+                    .tree(extent.begin)
+                    .unblocked_run(extent.cursor, extent.end)
+                else {
+                    // The rest of the extent is blocked, so move on without
+                    // disturbing any co-holders.
+                    drop(extent);
+                    self.drop_extent(&mut ctx);
+                    break;
+                };
+
+                match f(begin, end)? {
+                    Some(b) => {
+                        emit(b);
+                        count += 1;
+                        ctx.nr_allocated += 1;
+                        self.note_allocated(ctx.txn, b, 1);
+                        self.move_cursor(&mut extent, b + 1, 1);
+                    }
+                    None if end < extent.end => {
+                        // Try the run after the blocked range.
+                        self.move_cursor(&mut extent, end, 0);
+                    }
+                    None => {
+                        let end = extent.end;
+                        self.move_cursor(&mut extent, end, 0);
+                    }
+                }
+
+                if extent.cursor == extent.end {
+                    drop(extent);
+                    drop(ctx);
+                    self.reset_and_release(context.clone());
+                    break;
+                }
+            }
+        }
+
+        Ok(count)
+    }
+
+    // Allocates a run of len blocks starting on a multiple of align.  f is
+    // called with an aligned begin, and should allocate len contiguous
+    // blocks at an aligned offset within begin..end, returning the first.
+    // If the context's extent has no such run another is borrowed.  A run
+    // from f that's misaligned or outside the range it was offered is an
+    // error.  Like alloc(), it won't eat into reservations, or into the
+    // emergency reserve for a normal context.
+    pub fn alloc_aligned<F>(
+        &mut self,
+        context: &ContextGuard,
+        len: u64,
+        align: u64,
         mut f: F,
     ) -> io::Result<Option<u64>>
     where
         F: FnMut(u64, u64) -> io::Result<Option<u64>>,
     {
+        assert!(len > 0 && align > 0);
+        self.put_deferred();
+        let context = context.inner();
+
+        // Extents that have no suitable run.
+        let mut tried = Vec::new();
+
         loop {
             let mut ctx = context.lock().unwrap();
 
-            if ctx.extent.is_none() {
-                ctx.extent = self.extents.borrow();
+            if ctx.extent.is_none() && !self.borrow_extent(context, &mut ctx, &tried) {
+                return Ok(None); // -ENOSPC
+            }
 
-                #[allow(clippy::question_mark)]
-                if ctx.extent.is_none() {
-                    return Ok(None); // -ENOSPC
-                }
+            let extent_ref = ctx.extent.clone().unwrap();
+            let extent_begin = extent_ref.lock().unwrap().begin;
+            if self.may_be_held_back(extent_begin, ctx.priority, false)
+                && self.held_back(extent_begin, ctx.priority, false, len)
+            {
+                return Ok(None); // -ENOSPC
+            }
+            let mut extent = extent_ref.lock().unwrap();
 
-                let extent_begin = ctx.extent.as_ref().unwrap().lock().unwrap().begin;
-                self.add_holder(extent_begin, &context, &mut ctx);
+            // Runs below the high-water mark have already been searched.
+            let mut found = None;
+            let mut pos = extent.cursor.max(extent.aligned);
+            while let Some((begin, end)) = self.tree(extent.begin).unblocked_run(pos, extent.end) {
+                let aligned = begin.div_ceil(align) * align;
+                if aligned + len <= end {
+                    if let Some(b) = f(aligned, end)? {
+                        found = Some((b, aligned, end));
+                        break;
+                    }
+                }
+                pos = end;
             }
 
-            let mut extent = ctx.extent.as_ref().unwrap().lock().unwrap();
+            match found {
+                Some((b, begin, end)) => {
+                    // The run has to lie within what f was offered, which
+                    // excludes blocked and bad blocks.
+                    if !b.is_multiple_of(align) || b < begin || b + len > end {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("misaligned or out of range run at {}", b),
+                        ));
+                    }
+
+                    ctx.nr_allocated += len;
+                    self.note_allocated(ctx.txn, b, len);
+                    extent.aligned = b + len;
+
+                    // Leave the cursor alone if we skipped any blocks, so
+                    // the unaligned prefix can still be used.
+                    let cursor = if b == extent.cursor {
+                        b + len
+                    } else {
+                        extent.cursor
+                    };
+                    self.move_cursor(&mut extent, cursor, len);
 
-            match f(extent.cursor, extent.end) {
-                Ok(Some(b)) => {
-                    extent.cursor = b + 1;
                     if extent.cursor == extent.end {
                         drop(extent);
                         drop(ctx);
@@ -103,17 +991,34 @@ impl Allocator {
                     }
                     return Ok(Some(b));
                 }
-                Ok(None) => {
-                    extent.cursor = extent.end;
+                None => {
+                    // Only the unaligned prefix, if any, is left for alloc().
+                    extent.aligned = extent.end;
+                    tried.push(extent.begin);
                     drop(extent);
-                    drop(ctx);
-                    self.reset_and_release(context.clone());
+                    self.drop_extent(&mut ctx);
                 }
-                Err(e) => return Err(e),
             }
         }
     }
 
+    // Moves the extent's cursor on, having allocated used blocks from it.
+    // Blocks behind the cursor aren't counted as free, so the free count
+    // is clamped to what's left.
+    fn move_cursor(&mut self, extent: &mut Extent, cursor: u64, used: u64) {
+        let nr_free = extent.nr_free;
+        extent.nr_free = nr_free.saturating_sub(used).min(extent.end - cursor);
+
+        let moved = cursor != extent.cursor;
+        extent.cursor = cursor;
+
+        let tree = self.tree_mut(extent.begin);
+        tree.allocated(extent.begin, nr_free - extent.nr_free);
+        if moved {
+            tree.advanced(extent.begin, cursor);
+        }
+    }
+
     fn add_holder(
         &mut self,
         extent_begin: u64,
@@ -125,7 +1030,7 @@ impl Allocator {
             .and_modify(|head| {
                 ctx.next = Some(head.clone());
                 head.lock().unwrap().prev = Some(Arc::<Mutex<AllocContext>>::downgrade(context));
-                std::mem::swap(&mut context.clone(), head);
+                *head = context.clone();
             })
             .or_insert(context.clone());
     }
@@ -158,35 +1063,227 @@ impl Allocator {
         drop(ctx);
 
         let extent_begin = old_extent.as_ref().unwrap().lock().unwrap().begin;
-        self.reset_contexts(extent_begin);
-        self.extents.release(old_extent.unwrap());
+        self.reset_contexts(extent_begin, RevokeReason::Full);
+        self.tree_mut(extent_begin).release(old_extent.unwrap());
     }
 
-    fn reset_contexts(&mut self, extent_begin: u64) {
+    fn reset_contexts(&mut self, extent_begin: u64, reason: RevokeReason) {
         if let Some(holders) = self.holders.remove(&extent_begin) {
             let mut ac = holders.lock().unwrap();
-            reset_chained_contexts(&mut ac);
+            reset_chained_contexts(&mut ac, reason);
+        }
+    }
+
+    // Takes the extent away from every context whose extent intersects
+    // begin..end, and stops new borrows landing there until the range
+    // is unblocked.
+    pub fn revoke_range(&mut self, begin: u64, end: u64) {
+        self.put_deferred();
+        for class in &mut self.classes {
+            class.extents.block_range(begin, end);
+        }
+
+        let revoked: Vec<u64> = self
+            .holders
+            .iter()
+            .filter(|(_, head)| {
+                let range = head.lock().unwrap().extent_range().unwrap();
+                range.start < end && begin < range.end
+            })
+            .map(|(extent_begin, _)| *extent_begin)
+            .collect();
+
+        for extent_begin in revoked {
+            self.preempt(extent_begin, RevokeReason::Preempted);
+        }
+    }
+
+    // Resets every context holding the extent at extent_begin, and drops
+    // their holds.
+    fn preempt(&mut self, extent_begin: u64, reason: RevokeReason) {
+        let head = self.holders.remove(&extent_begin).unwrap();
+        let mut ac = head.lock().unwrap();
+        let extent = ac.extent.clone().unwrap();
+        let nr_holders = reset_chained_contexts(&mut ac, reason);
+        drop(ac);
+        self.tree_mut(extent_begin)
+            .release_holders(extent, nr_holders);
+    }
+
+    // Retires begin..end for good.  Contexts whose cursors are inside it
+    // are reset, and extents are cut back or split around it.  The blocks
+    // are never offered to the callback again, even after a reset or
+    // rescan, and the range is journalled.
+    pub fn mark_bad(&mut self, begin: u64, end: u64) {
+        self.put_deferred();
+
+        let positioned: Vec<u64> = self
+            .holders
+            .iter()
+            .filter(|(_, head)| {
+                let cursor = head.lock().unwrap().cursor();
+                cursor.is_some_and(|cursor| (begin..end).contains(&cursor))
+            })
+            .map(|(extent_begin, _)| *extent_begin)
+            .collect();
+        for extent_begin in positioned {
+            self.preempt(extent_begin, RevokeReason::BadBlocks);
+        }
+
+        for class in &mut self.classes {
+            class.extents.mark_bad(begin, end);
+        }
+        if let Some(discards) = &mut self.discards {
+            discards.allocated(begin, end);
         }
     }
 
-    fn reset_all_contexts(&mut self) {
+    pub fn bad_blocks(&self) -> RangeSet {
+        let mut bad = RangeSet::new();
+        for class in &self.classes {
+            for (begin, end) in class.extents.bad_blocks().iter() {
+                bad.insert(begin, end);
+            }
+        }
+        bad
+    }
+
+    pub fn unblock_range(&mut self, begin: u64, end: u64) {
+        for class in &mut self.classes {
+            class.extents.unblock_range(begin, end);
+        }
+    }
+
+    fn reset_all_contexts(&mut self, reason: RevokeReason) {
         let mut holders = BTreeMap::new();
         std::mem::swap(&mut holders, &mut self.holders);
 
         for (_, holders) in holders {
             let mut ac = holders.lock().unwrap();
-            reset_chained_contexts(&mut ac);
+            reset_chained_contexts(&mut ac, reason);
+        }
+    }
+
+    // Releases the extents of any contexts whose guards were dropped
+    // rather than put.
+    fn put_deferred(&mut self) {
+        let puts = std::mem::take(&mut self.deferred.lock().unwrap().puts);
+        for context in puts {
+            self.put_context_(context);
+        }
+    }
+
+    // The number of other contexts sharing this context's extent, from
+    // the tree's holder count.  Doesn't lock any other context.
+    pub fn nr_co_holders(&self, context: &ContextGuard) -> usize {
+        let ac = context.lock();
+        let Some(extent) = &ac.extent else {
+            return 0;
+        };
+        let begin = extent.lock().unwrap().begin;
+        self.classes
+            .iter()
+            .find(|c| c.extents.contains(begin))
+            .map_or(0, |c| c.extents.nr_holders_of(extent).saturating_sub(1))
+    }
+
+    // Call sites of get_context() for the contexts that are still out,
+    // ie. neither put nor dropped.  Anything left once every context
+    // should be back has been leaked, eg. with mem::forget().
+    #[cfg(debug_assertions)]
+    pub fn leaked_contexts(&self) -> Vec<&'static Location<'static>> {
+        self.deferred
+            .lock()
+            .unwrap()
+            .outstanding
+            .values()
+            .copied()
+            .collect()
+    }
+
+    pub fn checkpoint(&mut self) -> Checkpoint {
+        self.put_deferred();
+        Checkpoint {
+            trees: self
+                .classes
+                .iter()
+                .map(|c| c.extents.checkpoint())
+                .collect(),
+            txns: self.txns.clone(),
+            pending_frees: self.pending_frees.clone(),
         }
     }
 
+    // Restores the trees and transactions of a checkpoint.  Contexts keep
+    // their extents if they're still in the tree, otherwise they're
+    // reset.  The free counts are the checkpoint's until recount().
+    pub fn rollback(&mut self, checkpoint: &Checkpoint) {
+        assert!(checkpoint.trees.len() == self.classes.len());
+        self.put_deferred();
+
+        for (class, tree) in self.classes.iter_mut().zip(&checkpoint.trees) {
+            class.extents.rollback(tree);
+        }
+
+        let holders = std::mem::take(&mut self.holders);
+        for (extent_begin, head) in holders {
+            let mut ac = head.lock().unwrap();
+            let extent = ac.extent.clone().unwrap();
+            let nr_holders = nr_chained_contexts(&ac);
+
+            let held = self
+                .classes
+                .iter_mut()
+                .find(|c| c.extents.contains(extent_begin))
+                .is_some_and(|c| c.extents.hold(&extent, nr_holders));
+            if held {
+                drop(ac);
+                self.holders.insert(extent_begin, head);
+            } else {
+                reset_chained_contexts(&mut ac, RevokeReason::Rollback);
+            }
+        }
+
+        self.txns = checkpoint.txns.clone();
+        self.pending_frees = checkpoint.pending_frees.clone();
+    }
+
     pub fn reset(&mut self) {
-        self.reset_all_contexts();
-        self.extents.reset();
+        self.put_deferred();
+        self.reset_all_contexts(RevokeReason::Reset);
+        for class in &mut self.classes {
+            class.extents.reset();
+        }
     }
 
+    // Moves the end of the last class's region.  The other classes are
+    // reset, but otherwise unchanged.
     pub fn resize(&mut self, nr_blocks: u64) {
-        self.reset_all_contexts();
-        self.extents.resize(nr_blocks);
+        self.put_deferred();
+        self.reset_all_contexts(RevokeReason::Resize);
+
+        let (last, _) = self
+            .classes
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, c)| c.extents.region().1)
+            .unwrap();
+        for (i, class) in self.classes.iter_mut().enumerate() {
+            if i == last {
+                class.extents.resize(nr_blocks);
+            } else {
+                class.extents.reset();
+            }
+        }
+    }
+
+    // As resize(), but to the size of the space map, rebuilding the trees
+    // from it so the free counts match what's really there.
+    pub fn resize_from<S: SpaceMap + ?Sized>(&mut self, sm: &S) {
+        self.resize(sm.nr_blocks());
+        for class in &mut self.classes {
+            class.extents.rebuild(sm);
+        }
     }
 }
 
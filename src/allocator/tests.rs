@@ -1,19 +1,30 @@
 use anyhow::{ensure, Result};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use roaring::RoaringBitmap;
+use std::collections::BTreeSet;
+use std::path::Path;
 use std::sync::{Arc, Mutex};
 
 use crate::allocator::*;
+use crate::discard::*;
+use crate::journal::Event;
+use crate::space_map::disk::*;
+use crate::space_map::mmap::*;
+use crate::space_map::ref_count::*;
+use crate::space_map::*;
+use crate::test_utils::*;
 use crate::tree::utils::*;
 
 //----------------------------------------------------------------
 
 struct AllocationContext {
-    inner: Option<Arc<Mutex<AllocContext>>>,
+    inner: Option<ContextGuard>,
     blocks: Vec<u64>,
 }
 
 impl AllocationContext {
-    fn new(inner: Arc<Mutex<AllocContext>>) -> Self {
+    fn new(inner: ContextGuard) -> Self {
         Self {
             inner: Some(inner),
             blocks: Vec::new(),
@@ -24,7 +35,7 @@ impl AllocationContext {
     where
         F: FnMut(u64, u64) -> io::Result<Option<u64>>,
     {
-        match allocator.alloc(self.inner.as_ref().unwrap().clone(), f) {
+        match allocator.alloc(self.inner.as_ref().unwrap(), f) {
             Ok(Some(block)) => {
                 self.blocks.push(block);
                 Ok(Some(block))
@@ -54,12 +65,20 @@ fn preallocate_linear(allocated: &mut RoaringBitmap, count: u64, offset: u64) {
     allocated.insert_range((offset as u32)..(offset + count) as u32);
 }
 
-fn alloc_block(allocated: &mut RoaringBitmap, begin: u64, end: u64) -> io::Result<Option<u64>> {
-    for block in begin..end {
-        if !allocated.contains(block as u32) {
-            allocated.insert(block as u32);
-            return Ok(Some(block));
+fn alloc_aligned_run(
+    allocated: &mut RoaringBitmap,
+    begin: u64,
+    end: u64,
+    len: u64,
+    align: u64,
+) -> io::Result<Option<u64>> {
+    let mut b = begin;
+    while b + len <= end {
+        if allocated.range_cardinality((b as u32)..((b + len) as u32)) == 0 {
+            allocated.insert_range((b as u32)..((b + len) as u32));
+            return Ok(Some(b));
         }
+        b += align;
     }
     Ok(None)
 }
@@ -96,8 +115,8 @@ fn do_allocation_test(
         context_alloc(context, &mut allocator, &allocated)?;
     }
 
-    //   dump_tree(&allocator.extents);
-    //   draw_tree(&allocator.extents);
+    //   dump_tree(&allocator.classes[0].extents);
+    //   draw_tree(&allocator.classes[0].extents);
 
     let mut total_nr_allocated = 0;
     for (i, context) in contexts.iter_mut().enumerate() {
@@ -116,8 +135,8 @@ fn do_allocation_test(
     ensure!(total_nr_allocated == nr_blocks_to_allocate);
     ensure!(nr_allocated - nr_prealloc == nr_blocks_to_allocate);
 
-    dump_tree(&allocator.extents);
-    draw_tree(&allocator.extents);
+    dump_tree(&allocator.classes[0].extents);
+    draw_tree(&allocator.classes[0].extents);
 
     Ok(contexts)
 }
@@ -147,7 +166,7 @@ fn do_reset_test(nr_contexts: usize) -> Result<()> {
 
     for context in &mut contexts {
         let ctx = context.inner.as_ref().unwrap();
-        ensure!(ctx.lock().unwrap().extent.is_none());
+        ensure!(ctx.lock().extent_range().is_none());
     }
 
     Ok(())
@@ -381,4 +400,1385 @@ fn remove_holders_from_middle() -> Result<()> {
     })
 }
 
+#[test]
+fn dropped_context_releases_extent() -> Result<()> {
+    let nr_blocks = 1024;
+    let mut allocator = Allocator::new(nr_blocks, 3);
+    let allocated = Arc::new(Mutex::new(RoaringBitmap::new()));
+
+    let mut context = AllocationContext::new(allocator.get_context());
+    ensure!(matches!(
+        context_alloc(&mut context, &mut allocator, &allocated),
+        Ok(Some(_))
+    ));
+    ensure!(allocator.holders.len() == 1);
+
+    // The release is deferred until the allocator is next used.
+    drop(context);
+    ensure!(allocator.holders.len() == 1);
+
+    let context = allocator.get_context();
+    ensure!(allocator.holders.is_empty());
+    allocator.put_context(context);
+
+    // Dropping isn't leaking, but forgetting the guard is.
+    #[cfg(debug_assertions)]
+    {
+        ensure!(allocator.leaked_contexts().is_empty());
+        std::mem::forget(allocator.get_context());
+        ensure!(allocator.leaked_contexts().len() == 1);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn context_introspection() -> Result<()> {
+    let nr_blocks = 1024;
+    let mut allocator = Allocator::new(nr_blocks, 1);
+    let allocated = Arc::new(Mutex::new(RoaringBitmap::new()));
+
+    let mut contexts = vec![
+        AllocationContext::new(allocator.get_context()),
+        AllocationContext::new(allocator.get_context()),
+    ];
+
+    for context in &mut contexts {
+        ensure!(matches!(
+            context_alloc(context, &mut allocator, &allocated),
+            Ok(Some(_))
+        ));
+    }
+
+    {
+        let context = contexts[0].inner.as_ref().unwrap();
+        ensure!(allocator.nr_co_holders(context) == 1);
+        let ctx = context.lock();
+        ensure!(ctx.extent_range() == Some(0..nr_blocks));
+        ensure!(ctx.cursor() == Some(2));
+        ensure!(ctx.nr_allocated() == 1);
+        ensure!(ctx.nr_extent_switches() == 0);
+    }
+
+    allocator.reset();
+    ensure!(matches!(
+        context_alloc(&mut contexts[1], &mut allocator, &allocated),
+        Ok(Some(_))
+    ));
+
+    let context = contexts[1].inner.as_ref().unwrap();
+    ensure!(allocator.nr_co_holders(context) == 0);
+    let ctx = context.lock();
+    ensure!(ctx.nr_allocated() == 2);
+    ensure!(ctx.nr_extent_switches() == 1);
+
+    Ok(())
+}
+
+fn record_revocations(context: &AllocationContext) -> Arc<Mutex<Vec<Revocation>>> {
+    let revocations = Arc::new(Mutex::new(Vec::new()));
+    let r = revocations.clone();
+    context
+        .inner
+        .as_ref()
+        .unwrap()
+        .lock()
+        .on_revoke(move |revocation| r.lock().unwrap().push(*revocation));
+    revocations
+}
+
+#[test]
+fn revoke_on_full() -> Result<()> {
+    let nr_blocks = 1024;
+    let mut allocator = Allocator::new(nr_blocks, 1);
+    let allocated = Arc::new(Mutex::new(RoaringBitmap::new()));
+    preallocate_linear(&mut allocated.lock().unwrap(), nr_blocks - 2, 0);
+
+    let mut contexts = vec![
+        AllocationContext::new(allocator.get_context()),
+        AllocationContext::new(allocator.get_context()),
+    ];
+    let revocations: Vec<_> = contexts.iter().map(record_revocations).collect();
+
+    for context in &mut contexts {
+        ensure!(matches!(
+            context_alloc(context, &mut allocator, &allocated),
+            Ok(Some(_))
+        ));
+    }
+
+    let expected = Revocation {
+        reason: RevokeReason::Full,
+        begin: 0,
+        end: nr_blocks,
+    };
+    for r in revocations {
+        ensure!(*r.lock().unwrap() == vec![expected]);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn revoke_on_reset_and_resize() -> Result<()> {
+    let nr_blocks = 1024;
+    let mut allocator = Allocator::new(nr_blocks, 3);
+    let allocated = Arc::new(Mutex::new(RoaringBitmap::new()));
+
+    let mut context = AllocationContext::new(allocator.get_context());
+    let revocations = record_revocations(&context);
+
+    ensure!(matches!(
+        context_alloc(&mut context, &mut allocator, &allocated),
+        Ok(Some(_))
+    ));
+    allocator.reset();
+
+    // Contexts without an extent aren't notified.
+    allocator.reset();
+
+    ensure!(matches!(
+        context_alloc(&mut context, &mut allocator, &allocated),
+        Ok(Some(_))
+    ));
+    allocator.resize(2048);
+
+    let reasons: Vec<_> = revocations
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|r| r.reason)
+        .collect();
+    ensure!(reasons == vec![RevokeReason::Reset, RevokeReason::Resize]);
+
+    Ok(())
+}
+
+#[test]
+fn revoke_range_preempts_holders() -> Result<()> {
+    let nr_blocks = 1024;
+    let mut allocator = Allocator::new(nr_blocks, 3);
+    let allocated = Arc::new(Mutex::new(RoaringBitmap::new()));
+
+    let mut contexts = vec![
+        AllocationContext::new(allocator.get_context()),
+        AllocationContext::new(allocator.get_context()),
+    ];
+    let revocations: Vec<_> = contexts.iter().map(record_revocations).collect();
+
+    for context in &mut contexts {
+        ensure!(matches!(
+            context_alloc(context, &mut allocator, &allocated),
+            Ok(Some(_))
+        ));
+    }
+
+    allocator.revoke_range(600, 700);
+    check_nr_holders(&allocator.classes[0].extents)?;
+
+    ensure!(revocations[0].lock().unwrap().is_empty());
+    ensure!(
+        *revocations[1].lock().unwrap()
+            == vec![Revocation {
+                reason: RevokeReason::Preempted,
+                begin: 512,
+                end: nr_blocks,
+            }]
+    );
+
+    for _ in 0..400 {
+        ensure!(matches!(
+            context_alloc(&mut contexts[1], &mut allocator, &allocated),
+            Ok(Some(_))
+        ));
+    }
+    ensure!(contexts[1].blocks.iter().all(|b| !(600..700).contains(b)));
+
+    Ok(())
+}
+
+#[test]
+fn unblocked_range_can_be_allocated() -> Result<()> {
+    let nr_blocks = 1024;
+    let mut allocator = Allocator::new(nr_blocks, 3);
+    let mut sm = BitmapSpaceMap::new(nr_blocks);
+    let context = allocator.get_context();
+
+    allocator.revoke_range(100, 200);
+    for _ in 0..300 {
+        let b = allocator.alloc_from(&context, &mut sm)?;
+        ensure!(b.is_some_and(|b| !(100..200).contains(&b)));
+    }
+
+    allocator.unblock_range(100, 200);
+    let mut blocks = Vec::new();
+    while let Some(b) = allocator.alloc_from(&context, &mut sm)? {
+        blocks.push(b);
+    }
+    ensure!(sm.nr_free() == 0);
+    ensure!(blocks.iter().filter(|b| (100..200).contains(*b)).count() == 100);
+
+    allocator.put_context(context);
+    Ok(())
+}
+
+#[test]
+fn alloc_aligned_preserves_prefix() -> Result<()> {
+    let nr_blocks = 1024;
+    let mut allocator = Allocator::new(nr_blocks, 3);
+    let allocated = Arc::new(Mutex::new(RoaringBitmap::new()));
+
+    let mut context = AllocationContext::new(allocator.get_context());
+    ensure!(matches!(
+        context_alloc(&mut context, &mut allocator, &allocated),
+        Ok(Some(0))
+    ));
+
+    let b = allocator.alloc_aligned(context.inner.as_ref().unwrap(), 8, 64, |begin, end| {
+        alloc_aligned_run(&mut allocated.lock().unwrap(), begin, end, 8, 64)
+    })?;
+    ensure!(b == Some(64));
+
+    // The blocks between the cursor and the aligned run are still offered.
+    ensure!(context.inner.as_ref().unwrap().lock().cursor() == Some(1));
+    ensure!(matches!(
+        context_alloc(&mut context, &mut allocator, &allocated),
+        Ok(Some(1))
+    ));
+
+    // But aligned searches carry on from the last aligned run.
+    let mut first = None;
+    let b = allocator.alloc_aligned(context.inner.as_ref().unwrap(), 8, 64, |begin, end| {
+        first.get_or_insert(begin);
+        alloc_aligned_run(&mut allocated.lock().unwrap(), begin, end, 8, 64)
+    })?;
+    ensure!(first == Some(128) && b == Some(128));
+
+    Ok(())
+}
+
+#[test]
+fn skipped_blocks_leave_the_free_count() -> Result<()> {
+    let nr_blocks = 1024;
+    let mut allocator = Allocator::new(nr_blocks, 1);
+    let ctx = allocator.get_context();
+
+    // The blocks jumped over are behind the cursor, so no longer free.
+    let b = allocator.alloc(&ctx, |begin, _| Ok(Some(begin + 500)))?;
+    ensure!(b == Some(500));
+    ensure!(allocator.nr_free_blocks() == nr_blocks - 501);
+    check_extents(&allocator.classes[0].extents)?;
+
+    allocator.put_context(ctx);
+    Ok(())
+}
+
+#[test]
+fn alloc_aligned_rejects_bad_runs() -> Result<()> {
+    let mut allocator = Allocator::new(1024, 3);
+    let ctx = allocator.get_context();
+
+    let r = allocator.alloc_aligned(&ctx, 8, 64, |begin, _| Ok(Some(begin + 1)));
+    ensure!(r.map_err(|e| e.kind()) == Err(io::ErrorKind::InvalidData));
+
+    let r = allocator.alloc_aligned(&ctx, 8, 64, |_, end| Ok(Some(end)));
+    ensure!(r.map_err(|e| e.kind()) == Err(io::ErrorKind::InvalidData));
+
+    allocator.put_context(ctx);
+    Ok(())
+}
+
+#[test]
+fn alloc_aligned_borrows_another_extent() -> Result<()> {
+    let nr_blocks = 1024;
+    let mut allocator = Allocator::new(nr_blocks, 3);
+    let allocated = Arc::new(Mutex::new(RoaringBitmap::new()));
+
+    // Leave only unaligned holes in the right half.
+    {
+        let mut allocated = allocated.lock().unwrap();
+        preallocate_linear(&mut allocated, 512, 512);
+        for b in (520..nr_blocks).step_by(64) {
+            allocated.remove(b as u32);
+        }
+    }
+
+    let mut contexts = vec![
+        AllocationContext::new(allocator.get_context()),
+        AllocationContext::new(allocator.get_context()),
+    ];
+    for context in &mut contexts {
+        ensure!(matches!(
+            context_alloc(context, &mut allocator, &allocated),
+            Ok(Some(_))
+        ));
+    }
+
+    let ctx = contexts[1].inner.as_ref().unwrap();
+    ensure!(ctx.lock().extent_range() == Some(512..nr_blocks));
+
+    let b = allocator.alloc_aligned(ctx, 8, 64, |begin, end| {
+        alloc_aligned_run(&mut allocated.lock().unwrap(), begin, end, 8, 64)
+    })?;
+    ensure!(b == Some(64));
+    ensure!(ctx.lock().extent_range() == Some(0..512));
+    check_nr_holders(&allocator.classes[0].extents)?;
+
+    Ok(())
+}
+
+#[test]
+fn alloc_many_crosses_extents() -> Result<()> {
+    let nr_blocks = 1024;
+    let mut allocator = Allocator::new(nr_blocks, 7);
+    let allocated = Arc::new(Mutex::new(RoaringBitmap::new()));
+    preallocate_random(&mut allocated.lock().unwrap(), 100, 0..nr_blocks);
+
+    let contexts = [allocator.get_context(), allocator.get_context()];
+    let mut blocks = Vec::new();
+
+    let mut alloc_many = |allocator: &mut Allocator, context: &ContextGuard, n| {
+        allocator.alloc_many(context, n, &mut blocks, |begin, end| {
+            alloc_block(&mut allocated.lock().unwrap(), begin, end)
+        })
+    };
+
+    ensure!(alloc_many(&mut allocator, &contexts[0], 10)? == 10);
+    ensure!(alloc_many(&mut allocator, &contexts[1], 500)? == 500);
+
+    // Only a partial count is returned once we run out of space.
+    ensure!(alloc_many(&mut allocator, &contexts[0], 1000)? == nr_blocks - 610);
+    ensure!(alloc_many(&mut allocator, &contexts[1], 1)? == 0);
+
+    ensure!(blocks.len() as u64 == nr_blocks - 100);
+    let unique: RoaringBitmap = blocks.iter().map(|b| *b as u32).collect();
+    ensure!(unique.len() == blocks.len() as u64);
+
+    for context in contexts {
+        allocator.put_context(context);
+    }
+    ensure!(allocator.holders.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn reservation_guarantees_space() -> Result<()> {
+    let nr_blocks = 1024;
+    let mut allocator = Allocator::new(nr_blocks, 3);
+    let allocated = Arc::new(Mutex::new(RoaringBitmap::new()));
+
+    let mut reservation = allocator.reserve(1000).unwrap();
+    ensure!(allocator.reserve(100).is_none());
+    ensure!(allocator.nr_free_blocks() == 24);
+
+    // Unreserved allocations can't eat into the reservation.
+    let mut context = AllocationContext::new(allocator.get_context());
+    while let Ok(Some(_)) = context_alloc(&mut context, &mut allocator, &allocated) {}
+    ensure!(context.blocks.len() == 24);
+
+    let ctx = context.inner.as_ref().unwrap();
+    for _ in 0..10 {
+        let b = allocator.alloc_reserved(ctx, &mut reservation, |begin, end| {
+            alloc_block(&mut allocated.lock().unwrap(), begin, end)
+        })?;
+        ensure!(b.is_some());
+    }
+    ensure!(reservation.remaining() == 990);
+
+    // Dropping the reservation returns the unused blocks.
+    drop(reservation);
+    ensure!(allocator.nr_free_blocks() == 990);
+    ensure!(matches!(
+        context_alloc(&mut context, &mut allocator, &allocated),
+        Ok(Some(_))
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn reservation_holds_back_aligned_runs() -> Result<()> {
+    let mut allocator = Allocator::new(1024, 3);
+    let allocated = Arc::new(Mutex::new(RoaringBitmap::new()));
+    let _reservation = allocator.reserve(1000).unwrap();
+
+    // Only the 24 unreserved blocks can go.
+    let context = allocator.get_context();
+    let mut runs = 0;
+    while allocator
+        .alloc_aligned(&context, 8, 8, |begin, end| {
+            alloc_aligned_run(&mut allocated.lock().unwrap(), begin, end, 8, 8)
+        })?
+        .is_some()
+    {
+        runs += 1;
+    }
+    ensure!(runs == 3);
+    ensure!(allocated.lock().unwrap().len() == 24);
+
+    allocator.put_context(context);
+    Ok(())
+}
+
+#[test]
+fn emergency_reserve_is_privileged() -> Result<()> {
+    let nr_blocks = 1024;
+    let mut allocator = Allocator::new(nr_blocks, 3);
+    allocator.set_emergency_reserve(0.25);
+    ensure!(allocator.nr_free_blocks() == 768);
+
+    let allocated = Arc::new(Mutex::new(RoaringBitmap::new()));
+
+    let mut context = AllocationContext::new(allocator.get_context());
+    while let Ok(Some(_)) = context_alloc(&mut context, &mut allocator, &allocated) {}
+    ensure!(context.blocks.len() == 768);
+    ensure!(allocator.nr_free_blocks() == 0);
+
+    // A fresh normal context can't borrow into the reserve either.
+    let mut other = AllocationContext::new(allocator.get_context());
+    ensure!(matches!(
+        context_alloc(&mut other, &mut allocator, &allocated),
+        Ok(None)
+    ));
+
+    let mut privileged = AllocationContext::new(allocator.get_privileged_context());
+    while let Ok(Some(_)) = context_alloc(&mut privileged, &mut allocator, &allocated) {}
+    ensure!(privileged.blocks.len() == 256);
+
+    Ok(())
+}
+
+#[test]
+fn emergency_reserve_holds_back_aligned_runs() -> Result<()> {
+    let mut allocator = Allocator::new(1024, 3);
+    allocator.set_emergency_reserve(0.5);
+    let allocated = Arc::new(Mutex::new(RoaringBitmap::new()));
+
+    let alloc_runs = |allocator: &mut Allocator, context: &ContextGuard| -> Result<u64> {
+        let mut runs = 0;
+        while allocator
+            .alloc_aligned(context, 8, 8, |begin, end| {
+                alloc_aligned_run(&mut allocated.lock().unwrap(), begin, end, 8, 8)
+            })?
+            .is_some()
+        {
+            runs += 1;
+        }
+        Ok(runs)
+    };
+
+    let context = allocator.get_context();
+    ensure!(alloc_runs(&mut allocator, &context)? == 64);
+    allocator.put_context(context);
+
+    let privileged = allocator.get_privileged_context();
+    ensure!(alloc_runs(&mut allocator, &privileged)? == 64);
+    allocator.put_context(privileged);
+
+    Ok(())
+}
+
+fn two_classes(spill_to: Option<ClassId>) -> Allocator {
+    Allocator::with_classes(&[
+        ClassConfig {
+            spill_to,
+            ..ClassConfig::new(0, 64, 3)
+        },
+        ClassConfig::new(64, 1024, 7),
+    ])
+}
+
+#[test]
+fn classes_use_their_own_region() -> Result<()> {
+    let mut allocator = two_classes(None);
+    let allocated = Arc::new(Mutex::new(RoaringBitmap::new()));
+
+    let mut metadata = AllocationContext::new(allocator.get_context_in(0, Priority::Normal));
+    let mut data = AllocationContext::new(allocator.get_context_in(1, Priority::Normal));
+
+    for _ in 0..32 {
+        context_alloc(&mut metadata, &mut allocator, &allocated)?;
+        context_alloc(&mut data, &mut allocator, &allocated)?;
+    }
+    ensure!(metadata.blocks.iter().all(|b| *b < 64));
+    ensure!(data.blocks.iter().all(|b| *b >= 64));
+
+    while let Ok(Some(_)) = context_alloc(&mut metadata, &mut allocator, &allocated) {}
+    ensure!(metadata.blocks.len() == 64);
+    ensure!(allocator.nr_free_blocks_in(0) == 0);
+    ensure!(allocator.nr_free_blocks_in(1) == 960 - 32);
+
+    Ok(())
+}
+
+#[test]
+fn classes_spill_over() -> Result<()> {
+    let mut allocator = two_classes(Some(1));
+    let allocated = Arc::new(Mutex::new(RoaringBitmap::new()));
+
+    let mut metadata = AllocationContext::new(allocator.get_context_in(0, Priority::Normal));
+    for _ in 0..100 {
+        ensure!(matches!(
+            context_alloc(&mut metadata, &mut allocator, &allocated),
+            Ok(Some(_))
+        ));
+    }
+    ensure!(metadata.blocks.iter().filter(|b| **b < 64).count() == 64);
+
+    for class in &allocator.classes {
+        check_nr_holders(&class.extents)?;
+    }
+
+    Ok(())
+}
+
+#[test]
+fn rescan_recovers_skipped_blocks() -> Result<()> {
+    let nr_blocks = 1024;
+    let mut allocator = Allocator::new(nr_blocks, 7);
+    let mut sm = BitmapSpaceMap::new(nr_blocks);
+
+    let context = allocator.get_context();
+    while allocator.alloc_from(&context, &mut sm)?.is_some() {}
+    ensure!(sm.nr_free() == 0);
+
+    // Free some blocks behind the cursors.
+    for b in (0..nr_blocks).step_by(100) {
+        sm.mark_free(b)?;
+    }
+    ensure!(allocator.alloc_from(&context, &mut sm)?.is_none());
+
+    allocator.set_rescan(true);
+    let mut blocks = Vec::new();
+    while let Some(b) = allocator.alloc_from(&context, &mut sm)? {
+        blocks.push(b);
+    }
+    ensure!(blocks == (0..nr_blocks).step_by(100).collect::<Vec<_>>());
+    ensure!(sm.nr_free() == 0);
+
+    allocator.put_context(context);
+
+    Ok(())
+}
+
+#[test]
+fn rescan_ignores_reserved_space() -> Result<()> {
+    let nr_blocks = 1024;
+    let mut allocator = Allocator::new(nr_blocks, 7);
+    let mut sm = BitmapSpaceMap::new(nr_blocks);
+    allocator.set_rescan(true);
+
+    let revocations = Arc::new(Mutex::new(Vec::new()));
+    let first = allocator.get_context();
+    {
+        let revocations = revocations.clone();
+        first
+            .lock()
+            .on_revoke(move |r| revocations.lock().unwrap().push(r.reason));
+    }
+    ensure!(allocator.alloc_from(&first, &mut sm)?.is_some());
+
+    // Nothing is hidden from the trees, so there's no point rebuilding them.
+    let reservation = allocator.reserve(allocator.nr_free_blocks()).unwrap();
+    let second = allocator.get_context();
+    ensure!(allocator.alloc_from(&second, &mut sm)?.is_none());
+    ensure!(revocations.lock().unwrap().is_empty());
+    ensure!(first.lock().extent_range().is_some());
+
+    drop(reservation);
+    allocator.put_context(first);
+    allocator.put_context(second);
+    Ok(())
+}
+
+#[test]
+fn start_from_existing_space_map() -> Result<()> {
+    let nr_blocks = 1024;
+    let mut allocated = RoaringBitmap::new();
+    preallocate_linear(&mut allocated, nr_blocks * 7 / 10, 0);
+    preallocate_random(&mut allocated, 100, (nr_blocks * 7 / 10)..nr_blocks);
+
+    let mut sm = BitmapSpaceMap::from_allocated(nr_blocks, allocated.iter().map(u64::from))?;
+    let mut allocator = Allocator::from_space_map(&sm, 31);
+
+    let context = allocator.get_context();
+    let mut blocks = Vec::new();
+    while let Some(b) = allocator.alloc_from(&context, &mut sm)? {
+        ensure!(!allocated.contains(b as u32));
+        blocks.push(b);
+    }
+    ensure!(blocks.len() as u64 == nr_blocks - allocated.len());
+    allocator.put_context(context);
+
+    Ok(())
+}
+
+#[test]
+fn free_counts_track_space_map() -> Result<()> {
+    let nr_blocks = 4096;
+    let mut allocated = RoaringBitmap::new();
+    preallocate_random(&mut allocated, nr_blocks / 2, 0..nr_blocks);
+
+    let mut sm = BitmapSpaceMap::from_allocated(nr_blocks, allocated.iter().map(u64::from))?;
+    let mut allocator = Allocator::from_space_map(&sm, 63);
+    ensure!(allocator.nr_free_blocks() == sm.nr_free());
+
+    let contexts: Vec<_> = (0..8).map(|_| allocator.get_context()).collect();
+    let mut blocks = Vec::new();
+    for i in 0..1000 {
+        let b = allocator.alloc_from(&contexts[i % 8], &mut sm)?;
+        blocks.push(b.unwrap());
+    }
+    ensure!(allocator.nr_free_blocks() == sm.nr_free());
+
+    // Only blocks ahead of a cursor are counted until the next rescan.
+    let mut nr_counted = 0;
+    for b in blocks.iter().step_by(10) {
+        let before = allocator.nr_free_blocks();
+        allocator.free(&mut sm, *b)?;
+        nr_counted += allocator.nr_free_blocks() - before;
+    }
+    ensure!(allocator.nr_free_blocks() == sm.nr_free() - (100 - nr_counted));
+
+    for context in contexts {
+        allocator.put_context(context);
+    }
+    allocator.rescan(&sm);
+    ensure!(allocator.nr_free_blocks() == sm.nr_free());
+
+    Ok(())
+}
+
+#[test]
+fn shared_block_freed_on_last_reference() -> Result<()> {
+    let nr_blocks = 1024;
+    let b = 100;
+    let mut sm = RefCountSpaceMap::new(nr_blocks);
+    sm.mark_allocated(b)?;
+    let mut allocator = Allocator::from_space_map(&sm, 7);
+    let free = allocator.nr_free_blocks();
+
+    sm.inc_ref(b);
+    allocator.free(&mut sm, b)?;
+    ensure!(!sm.is_free(b));
+    ensure!(allocator.nr_free_blocks() == free);
+
+    allocator.free(&mut sm, b)?;
+    ensure!(sm.is_free(b));
+    ensure!(allocator.nr_free_blocks() == free + 1);
+
+    Ok(())
+}
+
+#[test]
+fn resize_with_mmap_space_map() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let mut sm = MmapSpaceMap::create(dir.path().join("space_map"), 1024)?;
+    let mut allocator = Allocator::from_space_map(&sm, 15);
+
+    let context = allocator.get_context();
+    while allocator.alloc_from(&context, &mut sm)?.is_some() {}
+    ensure!(sm.nr_free() == 0);
+
+    sm.resize(2048)?;
+    allocator.resize_from(&sm);
+    ensure!(allocator.nr_free_blocks() == sm.nr_free());
+    let b = allocator.alloc_from(&context, &mut sm)?;
+    ensure!(matches!(b, Some(b) if b >= 1024));
+    allocator.put_context(context);
+
+    Ok(())
+}
+
+#[test]
+fn recover_from_journal() -> Result<()> {
+    let nr_blocks = 4096;
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("journal");
+    let configs = [ClassConfig::new(0, nr_blocks, 31)];
+
+    let mut sm = BitmapSpaceMap::new(nr_blocks);
+    let mut allocator = Allocator::with_classes(&configs);
+    allocator.set_journal(Journal::create(&path)?);
+
+    let contexts: Vec<_> = (0..4).map(|_| allocator.get_context()).collect();
+    for i in 0..1000 {
+        let context = &contexts[i % contexts.len()];
+        allocator.alloc_from(context, &mut sm)?;
+    }
+    for context in contexts {
+        allocator.put_context(context);
+    }
+    allocator.sync_journal()?;
+
+    let shape = |tree: &Tree| {
+        tree.extents()
+            .iter()
+            .map(|e| (e.begin, e.end, e.cursor))
+            .collect::<Vec<_>>()
+    };
+    let before = shape(&allocator.classes[0].extents);
+    drop(allocator);
+
+    let recovered = Allocator::recover(&configs, &path, &sm)?;
+    ensure!(shape(&recovered.classes[0].extents) == before);
+    ensure!(recovered.nr_free_blocks() == sm.nr_free());
+
+    Ok(())
+}
+
+#[test]
+fn recover_rejects_mismatched_configs() -> Result<()> {
+    let nr_blocks = 4096;
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("journal");
+
+    let mut sm = BitmapSpaceMap::new(nr_blocks);
+    let mut allocator = Allocator::with_classes(&[ClassConfig::new(0, nr_blocks, 31)]);
+    allocator.set_journal(Journal::create(&path)?);
+
+    let contexts: Vec<_> = (0..4).map(|_| allocator.get_context()).collect();
+    for context in &contexts {
+        allocator.alloc_from(context, &mut sm)?;
+    }
+    for context in contexts {
+        allocator.put_context(context);
+    }
+    allocator.sync_journal()?;
+    ensure!(allocator.classes[0].extents.extents().len() >= 4);
+    drop(allocator);
+
+    // Too few nodes for the journalled extents, or a different region.
+    for configs in [
+        [ClassConfig::new(0, nr_blocks, 3)],
+        [ClassConfig::new(64, nr_blocks, 31)],
+    ] {
+        let r = Allocator::recover(&configs, &path, &sm);
+        ensure!(r.is_err_and(|e| e.kind() == io::ErrorKind::InvalidData));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn checkpoint_truncates_journal() -> Result<()> {
+    let nr_blocks = 4096;
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("journal");
+    let configs = [ClassConfig::new(0, nr_blocks, 31)];
+
+    let mut sm = BitmapSpaceMap::new(nr_blocks);
+    let mut allocator = Allocator::with_classes(&configs);
+    allocator.set_journal(Journal::create(&path)?);
+    allocator.mark_bad(100, 200);
+
+    let contexts: Vec<_> = (0..4).map(|_| allocator.get_context()).collect();
+    for i in 0..1000 {
+        let context = &contexts[i % contexts.len()];
+        allocator.alloc_from(context, &mut sm)?;
+        allocator.sync_journal()?;
+    }
+    let before = Journal::read(&path)?.len();
+
+    allocator.checkpoint_journal()?;
+    let records = Journal::read(&path)?;
+    ensure!(records.len() < before);
+    ensure!(matches!(records[0], (0, Event::Snapshot { .. })));
+
+    // Journalling carries on in the new file.
+    for i in 0..100 {
+        let context = &contexts[i % contexts.len()];
+        allocator.alloc_from(context, &mut sm)?;
+    }
+    for context in contexts {
+        allocator.put_context(context);
+    }
+    allocator.sync_journal()?;
+
+    let before = allocator.classes[0].extents.extents();
+    drop(allocator);
+
+    let recovered = Allocator::recover(&configs, &path, &sm)?;
+    let after = recovered.classes[0].extents.extents();
+    ensure!(before.len() == after.len());
+    for (b, a) in before.iter().zip(&after) {
+        ensure!((b.begin, b.end, b.cursor) == (a.begin, a.end, a.cursor));
+    }
+    ensure!(recovered.bad_blocks().nr_blocks() == 100);
+
+    Ok(())
+}
+
+#[test]
+fn abort_rewinds_cursor() -> Result<()> {
+    let nr_blocks = 1024;
+    let mut sm = BitmapSpaceMap::new(nr_blocks);
+    let mut allocator = Allocator::new(nr_blocks, 7);
+
+    let context = allocator.get_context();
+    allocator.alloc_from(&context, &mut sm)?;
+    let cursor = context.lock().cursor();
+    let free = allocator.nr_free_blocks();
+
+    context.lock().set_txn(Some(1));
+    for _ in 0..10 {
+        allocator.alloc_from(&context, &mut sm)?;
+    }
+    ensure!(allocator.provisional_blocks(1).len() == 10);
+
+    allocator.abort(1, &mut sm)?;
+    ensure!(allocator.provisional_blocks(1).is_empty());
+    ensure!(context.lock().cursor() == cursor);
+    ensure!(allocator.nr_free_blocks() == free);
+    ensure!(sm.nr_free() == nr_blocks - 1);
+
+    allocator.put_context(context);
+    Ok(())
+}
+
+#[test]
+fn abort_keeps_later_allocations() -> Result<()> {
+    let nr_blocks = 1024;
+    let mut sm = BitmapSpaceMap::new(nr_blocks);
+    let mut allocator = Allocator::new(nr_blocks, 7);
+
+    let context = allocator.get_context();
+    context.lock().set_txn(Some(1));
+    let b = allocator.alloc_from(&context, &mut sm)?.unwrap();
+    context.lock().set_txn(Some(2));
+    let later = allocator.alloc_from(&context, &mut sm)?.unwrap();
+
+    // The cursor can't move back past the later block.
+    allocator.abort(1, &mut sm)?;
+    ensure!(sm.is_free(b));
+    ensure!(context.lock().cursor() == Some(later + 1));
+
+    allocator.commit(2);
+    ensure!(allocator.provisional_blocks(2).is_empty());
+    ensure!(!sm.is_free(later));
+
+    allocator.put_context(context);
+    Ok(())
+}
+
+#[test]
+fn rollback_restores_cursors() -> Result<()> {
+    let nr_blocks = 1024;
+    let mut sm = BitmapSpaceMap::new(nr_blocks);
+    let mut allocator = Allocator::new(nr_blocks, 7);
+
+    let context = allocator.get_context();
+    for _ in 0..5 {
+        allocator.alloc_from(&context, &mut sm)?;
+    }
+    let checkpoint = allocator.checkpoint();
+    let saved = sm.clone();
+    ensure!(checkpoint.nr_holders() == 1);
+
+    let mut blocks = Vec::new();
+    for _ in 0..5 {
+        blocks.push(allocator.alloc_from(&context, &mut sm)?);
+    }
+
+    allocator.rollback(&checkpoint);
+    sm = saved;
+    ensure!(context.lock().cursor() == Some(5));
+    check_nr_holders(&allocator.classes[0].extents)?;
+
+    // The same blocks are handed out again.
+    for b in blocks {
+        ensure!(allocator.alloc_from(&context, &mut sm)? == b);
+    }
+
+    allocator.put_context(context);
+    Ok(())
+}
+
+#[test]
+fn rollback_recounts_free_blocks() -> Result<()> {
+    let nr_blocks = 1024;
+    let mut sm = BitmapSpaceMap::new(nr_blocks);
+    let mut allocator = Allocator::new(nr_blocks, 7);
+    let checkpoint = allocator.checkpoint();
+
+    // The blocks stay allocated in the space map, so the checkpoint's
+    // free counts are out of date.
+    let context = allocator.get_context();
+    for _ in 0..500 {
+        allocator.alloc_from(&context, &mut sm)?;
+    }
+    allocator.put_context(context);
+
+    allocator.rollback(&checkpoint);
+    allocator.recount(&sm);
+    ensure!(allocator.nr_free_blocks() == sm.nr_free());
+    ensure!(allocator.reserve(1000).is_none());
+
+    Ok(())
+}
+
+#[test]
+fn rollback_resets_contexts_on_lost_extents() -> Result<()> {
+    let nr_blocks = 1024;
+    let allocated = Arc::new(Mutex::new(RoaringBitmap::new()));
+    let mut allocator = Allocator::new(nr_blocks, 7);
+
+    let mut first = AllocationContext::new(allocator.get_context());
+    context_alloc(&mut first, &mut allocator, &allocated)?;
+    let checkpoint = allocator.checkpoint();
+
+    // The second context splits the first one's extent.
+    let mut second = AllocationContext::new(allocator.get_context());
+    let revocations = record_revocations(&second);
+    context_alloc(&mut second, &mut allocator, &allocated)?;
+
+    allocator.rollback(&checkpoint);
+    let first_ctx = first.inner.as_ref().unwrap().lock();
+    ensure!(first_ctx.extent_range() == Some(0..nr_blocks));
+    drop(first_ctx);
+    ensure!(second
+        .inner
+        .as_ref()
+        .unwrap()
+        .lock()
+        .extent_range()
+        .is_none());
+    ensure!(revocations.lock().unwrap()[0].reason == RevokeReason::Rollback);
+    check_nr_holders(&allocator.classes[0].extents)?;
+
+    allocator.put_context(first.inner.take().unwrap());
+    allocator.put_context(second.inner.take().unwrap());
+    Ok(())
+}
+
+#[test]
+fn deferred_frees_wait_for_commit() -> Result<()> {
+    let nr_blocks = 64;
+    let mut sm = BitmapSpaceMap::new(nr_blocks);
+    let mut allocator = Allocator::new(nr_blocks, 7);
+    allocator.set_rescan(true);
+
+    let context = allocator.get_context();
+    while allocator.alloc_from(&context, &mut sm)?.is_some() {}
+
+    allocator.defer_free(10, 20);
+    allocator.defer_free(20, 25);
+    ensure!(allocator.nr_pending_frees() == 15);
+    ensure!(allocator.alloc_from(&context, &mut sm)?.is_none());
+
+    ensure!(allocator.commit_frees(&mut sm)? == 15);
+    ensure!(allocator.nr_pending_frees() == 0);
+    ensure!(sm.nr_free() == 15);
+    let b = allocator.alloc_from(&context, &mut sm)?;
+    ensure!(matches!(b, Some(b) if (10..25).contains(&b)));
+
+    allocator.put_context(context);
+    Ok(())
+}
+
+// Refuses to free one block.
+struct Stubborn(BitmapSpaceMap, u64);
+
+impl SpaceMap for Stubborn {
+    fn nr_blocks(&self) -> u64 {
+        self.0.nr_blocks()
+    }
+    fn nr_free(&self) -> u64 {
+        self.0.nr_free()
+    }
+    fn is_free(&self, b: u64) -> bool {
+        self.0.is_free(b)
+    }
+    fn find_free(&self, begin: u64, end: u64) -> Option<u64> {
+        self.0.find_free(begin, end)
+    }
+    fn mark_allocated(&mut self, b: u64) -> io::Result<()> {
+        self.0.mark_allocated(b)
+    }
+    fn mark_free(&mut self, b: u64) -> io::Result<()> {
+        if b == self.1 {
+            return Err(io::Error::other("stubborn"));
+        }
+        self.0.mark_free(b)
+    }
+}
+
+#[test]
+fn failed_commit_keeps_frees_pending() -> Result<()> {
+    let nr_blocks = 64;
+    let mut sm = Stubborn(BitmapSpaceMap::new(nr_blocks), 15);
+    let mut allocator = Allocator::new(nr_blocks, 7);
+
+    let context = allocator.get_context();
+    while allocator.alloc_from(&context, &mut sm)?.is_some() {}
+    allocator.put_context(context);
+
+    allocator.defer_free(10, 20);
+    allocator.defer_free(30, 35);
+    ensure!(allocator.commit_frees(&mut sm).is_err());
+    ensure!(sm.nr_free() == 5);
+    ensure!(allocator.nr_pending_frees() == 10);
+
+    sm.1 = u64::MAX;
+    ensure!(allocator.commit_frees(&mut sm)? == 10);
+    ensure!(sm.nr_free() == 15);
+
+    Ok(())
+}
+
+#[test]
+fn failed_abort_keeps_blocks_in_txn() -> Result<()> {
+    let nr_blocks = 64;
+    let mut sm = Stubborn(BitmapSpaceMap::new(nr_blocks), u64::MAX);
+    let mut allocator = Allocator::new(nr_blocks, 7);
+
+    let context = allocator.get_context();
+    context.lock().set_txn(Some(1));
+    let mut blocks = Vec::new();
+    for _ in 0..10 {
+        blocks.push(allocator.alloc_from(&context, &mut sm)?.unwrap());
+    }
+    allocator.put_context(context);
+
+    // Blocks are freed last first, so those up to and including the
+    // stubborn one are still allocated.
+    sm.1 = blocks[4];
+    ensure!(allocator.abort(1, &mut sm).is_err());
+    ensure!(allocator.provisional_blocks(1) == blocks[..5]);
+    ensure!(sm.nr_free() == nr_blocks - 5);
+
+    sm.1 = u64::MAX;
+    allocator.abort(1, &mut sm)?;
+    ensure!(allocator.provisional_blocks(1).is_empty());
+    ensure!(sm.nr_free() == nr_blocks);
+
+    Ok(())
+}
+
+#[test]
+fn freed_blocks_are_discarded() -> Result<()> {
+    let nr_blocks = 1024;
+    let mut sm = BitmapSpaceMap::new(nr_blocks);
+    let mut allocator = Allocator::new(nr_blocks, 7);
+    let recorder = Recorder::default();
+    allocator.set_discard_tracker(DiscardTracker::new(Box::new(recorder.clone()), 4, 1000));
+
+    let context = allocator.get_context();
+    for _ in 0..32 {
+        allocator.alloc_from(&context, &mut sm)?;
+    }
+    for b in 2..30 {
+        allocator.free(&mut sm, b)?;
+    }
+    allocator.put_context(context);
+
+    allocator.flush_discards()?;
+    ensure!(*recorder.discards.lock().unwrap() == vec![(4, 28)]);
+
+    Ok(())
+}
+
+#[test]
+fn failed_discard_still_frees() -> Result<()> {
+    // Fails every discard while broken is set.
+    #[derive(Clone, Default)]
+    struct Flaky {
+        broken: Arc<Mutex<bool>>,
+        recorder: Recorder,
+    }
+
+    impl DiscardSink for Flaky {
+        fn discard(&mut self, begin: u64, end: u64) -> io::Result<()> {
+            if *self.broken.lock().unwrap() {
+                return Err(io::Error::other("flaky"));
+            }
+            self.recorder.discard(begin, end)
+        }
+    }
+
+    let nr_blocks = 64;
+    let mut sm = BitmapSpaceMap::new(nr_blocks);
+    let mut allocator = Allocator::new(nr_blocks, 7);
+    let sink = Flaky::default();
+    allocator.set_discard_tracker(DiscardTracker::new(Box::new(sink.clone()), 1, 1));
+
+    let context = allocator.get_context();
+    let b = allocator.alloc_from(&context, &mut sm)?.unwrap();
+    allocator.put_context(context);
+
+    // The block is freed, once, even though the discard failed.
+    *sink.broken.lock().unwrap() = true;
+    allocator.free(&mut sm, b)?;
+    ensure!(sm.nr_free() == nr_blocks);
+    ensure!(allocator.flush_discards().is_err());
+
+    *sink.broken.lock().unwrap() = false;
+    allocator.flush_discards()?;
+    ensure!(*sink.recorder.discards.lock().unwrap() == vec![(b, b + 1)]);
+
+    Ok(())
+}
+
+#[test]
+fn bad_blocks_are_never_offered() -> Result<()> {
+    let nr_blocks = 1024;
+    let allocated = Arc::new(Mutex::new(RoaringBitmap::new()));
+    let mut allocator = Allocator::new(nr_blocks, 15);
+
+    let mut context = AllocationContext::new(allocator.get_context());
+    let revocations = record_revocations(&context);
+    context_alloc(&mut context, &mut allocator, &allocated)?;
+
+    // The context is positioned inside the first range, the second is
+    // in the middle of its extent.
+    allocator.mark_bad(0, 10);
+    allocator.mark_bad(500, 520);
+    ensure!(revocations.lock().unwrap()[0].reason == RevokeReason::BadBlocks);
+    for extent in allocator.classes[0].extents.extents() {
+        ensure!(extent.end <= 500 || extent.cursor >= 520);
+    }
+    check_nr_holders(&allocator.classes[0].extents)?;
+    check_extents(&allocator.classes[0].extents)?;
+
+    while context_alloc(&mut context, &mut allocator, &allocated)?.is_some() {}
+    allocator.reset();
+    while context_alloc(&mut context, &mut allocator, &allocated)?.is_some() {}
+    allocator.put_context(context.inner.take().unwrap());
+
+    let bad = allocator.bad_blocks();
+    ensure!(bad.iter().collect::<Vec<_>>() == vec![(0, 10), (500, 520)]);
+    ensure!(context.blocks.iter().skip(1).all(|b| !bad.contains(*b)));
+    ensure!(allocated.lock().unwrap().len() == nr_blocks - 30 + 1);
+
+    Ok(())
+}
+
+#[test]
+fn bad_blocks_are_not_counted_free() -> Result<()> {
+    let nr_blocks = 1024;
+    let mut sm = BitmapSpaceMap::new(nr_blocks);
+    let mut allocator = Allocator::new(nr_blocks, 15);
+    allocator.mark_bad(0, 512);
+    ensure!(allocator.nr_free_blocks() == 512);
+
+    allocator.reset();
+    ensure!(allocator.nr_free_blocks() == 512);
+    ensure!(allocator.reserve(513).is_none());
+
+    allocator.rescan(&sm);
+    ensure!(allocator.nr_free_blocks() == 512);
+
+    sm.mark_allocated(600)?;
+    allocator.resize_from(&sm);
+    ensure!(allocator.nr_free_blocks() == 511);
+
+    Ok(())
+}
+
+#[test]
+fn alloc_aligned_rejects_runs_over_bad_blocks() -> Result<()> {
+    let mut allocator = Allocator::new(1024, 1);
+    allocator.mark_bad(100, 110);
+    let context = allocator.get_context();
+
+    // f is offered 0..100, but claims a run that crosses into the bad
+    // blocks.
+    let r = allocator.alloc_aligned(&context, 16, 16, |begin, end| {
+        assert!((begin, end) == (0, 100));
+        Ok(Some(96))
+    });
+    ensure!(r.is_err_and(|e| e.kind() == io::ErrorKind::InvalidData));
+
+    allocator.put_context(context);
+    Ok(())
+}
+
+#[test]
+fn bad_blocks_are_journalled() -> Result<()> {
+    let nr_blocks = 1024;
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("journal");
+    let configs = [ClassConfig::new(0, nr_blocks, 15)];
+
+    let mut allocator = Allocator::with_classes(&configs);
+    allocator.set_journal(Journal::create(&path)?);
+    allocator.mark_bad(100, 200);
+    allocator.sync_journal()?;
+    drop(allocator);
+
+    let mut sm = BitmapSpaceMap::new(nr_blocks);
+    let mut allocator = Allocator::recover(&configs, &path, &sm)?;
+    ensure!(allocator.bad_blocks().iter().collect::<Vec<_>>() == vec![(100, 200)]);
+
+    allocator.set_rescan(true);
+    let context = allocator.get_context();
+    while let Some(b) = allocator.alloc_from(&context, &mut sm)? {
+        ensure!(!(100..200).contains(&b));
+    }
+    allocator.put_context(context);
+    ensure!(sm.nr_free() == 100);
+
+    Ok(())
+}
+
+//----------------------------------------------------------------
+
+// Crash consistency.
+//
+// The persistence layer is a DiskSpaceMap and the allocator's journal.
+// At each sync point the space map is flushed, then the journal synced.
+// A crash is simulated at every write in between: after each of the
+// space map's block writes, part way through each of them, and with
+// the journal truncated, or torn, anywhere in the new records.  Every
+// one of those states has to recover.
+
+struct SyncPoint {
+    // The files before and after the sync
+    sm: (Vec<u8>, Vec<u8>),
+    journal: (Vec<u8>, Vec<u8>),
+
+    // Blocks that must survive a crash during this sync, ie. those
+    // allocated at the previous sync and not freed since.
+    handed_out: BTreeSet<u64>,
+}
+
+fn crash_configs() -> Vec<ClassConfig> {
+    vec![ClassConfig::new(0, 2 * ENTRIES_PER_BITMAP, 31)]
+}
+
+fn run_crash_workload(dir: &Path) -> Result<Vec<SyncPoint>> {
+    let sm_path = dir.join("space_map");
+    let journal_path = dir.join("journal");
+
+    let mut sm = DiskSpaceMap::create(&sm_path, 2 * ENTRIES_PER_BITMAP)?;
+    let mut allocator = Allocator::with_classes(&crash_configs());
+    allocator.set_journal(Journal::create(&journal_path)?);
+    allocator.sync_journal()?;
+
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut contexts: Vec<_> = (0..3).map(|_| allocator.get_context()).collect();
+    let mut live = BTreeSet::new();
+    let mut acked = BTreeSet::new();
+    let mut points = Vec::new();
+
+    for _ in 0..8 {
+        for _ in 0..30 {
+            let context = &contexts[rng.gen_range(0..contexts.len())];
+            if let Some(b) = allocator.alloc_from(context, &mut sm)? {
+                live.insert(b);
+            }
+        }
+        for _ in 0..5 {
+            let b = *live.iter().nth(rng.gen_range(0..live.len())).unwrap();
+            allocator.free(&mut sm, b)?;
+            live.remove(&b);
+        }
+
+        // Swap a context, so extents get released.
+        let context = allocator.get_context();
+        let i = rng.gen_range(0..contexts.len());
+        allocator.put_context(std::mem::replace(&mut contexts[i], context));
+
+        let sm_before = std::fs::read(&sm_path)?;
+        let journal_before = std::fs::read(&journal_path)?;
+        sm.flush()?;
+        allocator.sync_journal()?;
+        points.push(SyncPoint {
+            sm: (sm_before, std::fs::read(&sm_path)?),
+            journal: (journal_before, std::fs::read(&journal_path)?),
+            handed_out: acked.intersection(&live).copied().collect(),
+        });
+        acked = live.clone();
+    }
+
+    for context in contexts {
+        allocator.put_context(context);
+    }
+    Ok(points)
+}
+
+// The (space map, journal) files a crash during the sync could leave.
+fn crash_states(point: &SyncPoint) -> Vec<(Vec<u8>, Vec<u8>)> {
+    let (sm_before, sm_after) = &point.sm;
+    let (journal_before, journal_after) = &point.journal;
+    let mut states = Vec::new();
+
+    // flush() writes the changed bitmaps and the index, then commits by
+    // writing a superblock to block 0 or 1.
+    let mut locs: Vec<usize> = (0..sm_after.len() / BLOCK_SIZE)
+        .filter(|loc| {
+            let block = loc * BLOCK_SIZE..(loc + 1) * BLOCK_SIZE;
+            sm_before[block.clone()] != sm_after[block]
+        })
+        .collect();
+    locs.sort_by_key(|loc| (*loc < 2, *loc));
+
+    let mut sm = sm_before.clone();
+    states.push((sm.clone(), journal_before.clone()));
+    for loc in locs {
+        // Only the first half of the block made it.
+        let half = loc * BLOCK_SIZE..loc * BLOCK_SIZE + BLOCK_SIZE / 2;
+        let mut torn = sm.clone();
+        torn[half.clone()].copy_from_slice(&sm_after[half]);
+        states.push((torn, journal_before.clone()));
+
+        let block = loc * BLOCK_SIZE..(loc + 1) * BLOCK_SIZE;
+        sm[block.clone()].copy_from_slice(&sm_after[block]);
+        states.push((sm.clone(), journal_before.clone()));
+    }
+
+    for len in (journal_before.len()..journal_after.len()).step_by(11) {
+        let mut journal = journal_after[..len].to_vec();
+        states.push((sm_after.clone(), journal.clone()));
+        journal.extend_from_slice(&[0xa5; 7]);
+        states.push((sm_after.clone(), journal));
+    }
+    states.push((sm_after.clone(), journal_after.clone()));
+
+    states
+}
+
+fn check_recovery(dir: &Path, sm: &[u8], journal: &[u8], handed_out: &BTreeSet<u64>) -> Result<()> {
+    let sm_path = dir.join("crashed_space_map");
+    let journal_path = dir.join("crashed_journal");
+    std::fs::write(&sm_path, sm)?;
+    std::fs::write(&journal_path, journal)?;
+
+    let mut sm = DiskSpaceMap::open(&sm_path)?;
+    for b in handed_out {
+        ensure!(!sm.is_free(*b), "block {} was handed out, but is free", b);
+    }
+
+    let mut allocator = Allocator::recover(&crash_configs(), &journal_path, &sm)?;
+    for class in &allocator.classes {
+        check_nr_holders(&class.extents)?;
+        check_extents(&class.extents)?;
+    }
+
+    let context = allocator.get_context();
+    for _ in 0..8 {
+        if let Some(b) = allocator.alloc_from(&context, &mut sm)? {
+            ensure!(!handed_out.contains(&b), "block {} handed out twice", b);
+        }
+    }
+    allocator.put_context(context);
+
+    Ok(())
+}
+
+#[test]
+fn crash_at_every_write() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let points = run_crash_workload(dir.path())?;
+
+    for point in &points {
+        for (sm, journal) in crash_states(point) {
+            check_recovery(dir.path(), &sm, &journal, &point.handed_out)?;
+        }
+    }
+
+    Ok(())
+}
+
 //----------------------------------------------------------------
@@ -0,0 +1,77 @@
+use std::io;
+
+use crate::range_set::RangeSet;
+
+#[cfg(test)]
+mod tests;
+
+//----------------------------------------------------------------
+
+// Receives batches of blocks to discard (TRIM).
+pub trait DiscardSink {
+    fn discard(&mut self, begin: u64, end: u64) -> io::Result<()>;
+}
+
+// Collects freed blocks, coalescing them into runs, and discards the
+// parts of those runs that cover whole granules.  Ragged ends are kept
+// back in case their neighbours are freed later.
+pub struct DiscardTracker {
+    sink: Box<dyn DiscardSink + Send>,
+    granularity: u64,
+    pending: RangeSet,
+
+    // Discard once this many blocks have been freed.
+    batch_size: u64,
+    nr_freed: u64,
+}
+
+impl DiscardTracker {
+    pub fn new(sink: Box<dyn DiscardSink + Send>, granularity: u64, batch_size: u64) -> Self {
+        assert!(granularity > 0);
+        Self {
+            sink,
+            granularity,
+            pending: RangeSet::new(),
+            batch_size,
+            nr_freed: 0,
+        }
+    }
+
+    pub fn freed(&mut self, begin: u64, end: u64) -> io::Result<()> {
+        self.pending.insert(begin, end);
+        self.nr_freed += end - begin;
+        if self.nr_freed >= self.batch_size {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    // Blocks that are reused must not be discarded.
+    pub fn allocated(&mut self, begin: u64, end: u64) {
+        self.pending.remove(begin, end);
+    }
+
+    pub fn nr_pending(&self) -> u64 {
+        self.pending.nr_blocks()
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.nr_freed = 0;
+
+        let g = self.granularity;
+        let runs: Vec<(u64, u64)> = self
+            .pending
+            .iter()
+            .map(|(begin, end)| (begin.div_ceil(g) * g, end / g * g))
+            .filter(|(begin, end)| begin < end)
+            .collect();
+
+        for (begin, end) in runs {
+            self.sink.discard(begin, end)?;
+            self.pending.remove(begin, end);
+        }
+        Ok(())
+    }
+}
+
+//----------------------------------------------------------------
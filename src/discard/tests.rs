@@ -0,0 +1,50 @@
+use anyhow::{ensure, Result};
+
+use crate::discard::*;
+use crate::test_utils::*;
+
+//----------------------------------------------------------------
+
+#[test]
+fn discards_are_coalesced() -> Result<()> {
+    let recorder = Recorder::default();
+    let mut tracker = DiscardTracker::new(Box::new(recorder.clone()), 1, 1000);
+
+    for b in (0..10).chain(20..30).chain(10..20) {
+        tracker.freed(b, b + 1)?;
+    }
+    tracker.freed(50, 60)?;
+    ensure!(recorder.discards.lock().unwrap().is_empty());
+
+    tracker.flush()?;
+    ensure!(*recorder.discards.lock().unwrap() == vec![(0, 30), (50, 60)]);
+    ensure!(tracker.nr_pending() == 0);
+
+    Ok(())
+}
+
+#[test]
+fn discards_respect_granularity() -> Result<()> {
+    let recorder = Recorder::default();
+    let mut tracker = DiscardTracker::new(Box::new(recorder.clone()), 8, 10);
+
+    tracker.freed(3, 12)?;
+    tracker.flush()?;
+    ensure!(recorder.discards.lock().unwrap().is_empty());
+
+    // Freeing the neighbours completes the granules, and fills the batch.
+    tracker.freed(12, 19)?;
+    tracker.freed(0, 3)?;
+    ensure!(*recorder.discards.lock().unwrap() == vec![(0, 16)]);
+    ensure!(tracker.nr_pending() == 3);
+
+    // Reallocated blocks are never discarded.
+    tracker.freed(19, 24)?;
+    tracker.allocated(20, 21);
+    tracker.flush()?;
+    ensure!(recorder.discards.lock().unwrap().len() == 1);
+
+    Ok(())
+}
+
+//----------------------------------------------------------------
@@ -0,0 +1,345 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::allocator::ClassId;
+use crate::range_set::RangeSet;
+
+#[cfg(test)]
+mod tests;
+
+//----------------------------------------------------------------
+
+// Mutations of a tree, identified by the begin of the extent they touch.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    // The tree was reset or rebuilt, and now holds these extents,
+    // as (begin, end, cursor).
+    Snapshot {
+        begin: u64,
+        end: u64,
+        extents: Vec<(u64, u64, u64)>,
+    },
+    Borrow {
+        begin: u64,
+    },
+    Split {
+        begin: u64,
+        cut: u64,
+    },
+    Advance {
+        begin: u64,
+        cursor: u64,
+    },
+    Release {
+        begin: u64,
+    },
+    Free {
+        b: u64,
+    },
+    Bad {
+        begin: u64,
+        end: u64,
+    },
+}
+
+const SNAPSHOT: u8 = 0;
+const BORROW: u8 = 1;
+const SPLIT: u8 = 2;
+const ADVANCE: u8 = 3;
+const RELEASE: u8 = 4;
+const FREE: u8 = 5;
+const BAD: u8 = 6;
+
+fn put_u64(buf: &mut Vec<u8>, v: u64) {
+    buf.extend_from_slice(&v.to_le_bytes());
+}
+
+fn encode(class: ClassId, event: &Event) -> Vec<u8> {
+    let mut payload = Vec::new();
+    payload.extend_from_slice(&(class as u32).to_le_bytes());
+    match event {
+        Event::Snapshot {
+            begin,
+            end,
+            extents,
+        } => {
+            payload.push(SNAPSHOT);
+            put_u64(&mut payload, *begin);
+            put_u64(&mut payload, *end);
+            put_u64(&mut payload, extents.len() as u64);
+            for (b, e, cursor) in extents {
+                put_u64(&mut payload, *b);
+                put_u64(&mut payload, *e);
+                put_u64(&mut payload, *cursor);
+            }
+        }
+        Event::Borrow { begin } => {
+            payload.push(BORROW);
+            put_u64(&mut payload, *begin);
+        }
+        Event::Split { begin, cut } => {
+            payload.push(SPLIT);
+            put_u64(&mut payload, *begin);
+            put_u64(&mut payload, *cut);
+        }
+        Event::Advance { begin, cursor } => {
+            payload.push(ADVANCE);
+            put_u64(&mut payload, *begin);
+            put_u64(&mut payload, *cursor);
+        }
+        Event::Release { begin } => {
+            payload.push(RELEASE);
+            put_u64(&mut payload, *begin);
+        }
+        Event::Free { b } => {
+            payload.push(FREE);
+            put_u64(&mut payload, *b);
+        }
+        Event::Bad { begin, end } => {
+            payload.push(BAD);
+            put_u64(&mut payload, *begin);
+            put_u64(&mut payload, *end);
+        }
+    }
+
+    // Each record is prefixed with its length and a checksum, so a torn
+    // tail can be recognised.
+    let mut record = Vec::with_capacity(payload.len() + 8);
+    record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    record.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    record.extend_from_slice(&payload);
+    record
+}
+
+fn decode(payload: &[u8]) -> Option<(ClassId, Event)> {
+    let mut words = payload
+        .get(5..)?
+        .chunks_exact(8)
+        .map(|w| u64::from_le_bytes(w.try_into().unwrap()));
+    let mut next = || words.next();
+
+    let class = u32::from_le_bytes(payload.get(0..4)?.try_into().unwrap()) as ClassId;
+    let event = match payload[4] {
+        SNAPSHOT => {
+            let begin = next()?;
+            let end = next()?;
+            let mut extents = Vec::new();
+            for _ in 0..next()? {
+                extents.push((next()?, next()?, next()?));
+            }
+            Event::Snapshot {
+                begin,
+                end,
+                extents,
+            }
+        }
+        BORROW => Event::Borrow { begin: next()? },
+        SPLIT => Event::Split {
+            begin: next()?,
+            cut: next()?,
+        },
+        ADVANCE => Event::Advance {
+            begin: next()?,
+            cursor: next()?,
+        },
+        RELEASE => Event::Release { begin: next()? },
+        FREE => Event::Free { b: next()? },
+        BAD => Event::Bad {
+            begin: next()?,
+            end: next()?,
+        },
+        _ => return None,
+    };
+    Some((class, event))
+}
+
+// Reads the records from a journal, stopping at the first one that's
+// incomplete or corrupt.  Returns them with the length of the good
+// prefix of the file.
+fn read_records(file: &mut File) -> io::Result<(Vec<(ClassId, Event)>, u64)> {
+    let mut data = Vec::new();
+    file.read_to_end(&mut data)?;
+
+    let mut records = Vec::new();
+    let mut pos = 0;
+    while pos + 8 <= data.len() {
+        let len = u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap()) as usize;
+        let csum = u32::from_le_bytes(data[pos + 4..pos + 8].try_into().unwrap());
+        let Some(payload) = data.get(pos + 8..pos + 8 + len) else {
+            break;
+        };
+        if crc32fast::hash(payload) != csum {
+            break;
+        }
+        let Some(record) = decode(payload) else {
+            break;
+        };
+        records.push(record);
+        pos += 8 + len;
+    }
+
+    Ok((records, pos as u64))
+}
+
+//----------------------------------------------------------------
+
+// An append-only log of tree events.  Events are buffered until sync().
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+    buf: Vec<u8>,
+}
+
+impl Journal {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+        Ok(Self {
+            path: path.as_ref().to_path_buf(),
+            file,
+            buf: Vec::new(),
+        })
+    }
+
+    // Opens an existing journal for appending, returning the records
+    // already in it.  Any torn tail is discarded.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<(Self, Vec<(ClassId, Event)>)> {
+        let mut file = OpenOptions::new().read(true).write(true).open(&path)?;
+        let (records, len) = read_records(&mut file)?;
+        file.set_len(len)?;
+        file.seek(SeekFrom::Start(len))?;
+        let journal = Self {
+            path: path.as_ref().to_path_buf(),
+            file,
+            buf: Vec::new(),
+        };
+        Ok((journal, records))
+    }
+
+    pub fn read<P: AsRef<Path>>(path: P) -> io::Result<Vec<(ClassId, Event)>> {
+        let mut file = File::open(path)?;
+        Ok(read_records(&mut file)?.0)
+    }
+
+    pub fn append(&mut self, class: ClassId, event: &Event) {
+        self.buf.extend_from_slice(&encode(class, event));
+    }
+
+    // Writes out the buffered events, and waits for them to hit the disk.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.file.write_all(&self.buf)?;
+        self.buf.clear();
+        self.file.sync_data()
+    }
+
+    // Replaces everything in the journal with records, which should start
+    // afresh from a snapshot, so the journal doesn't grow without bound.
+    // They're written to a new file that's renamed over the old one, so a
+    // crash leaves one or the other.  Buffered events are superseded, and
+    // dropped.  If the rename doesn't happen, the records stay buffered
+    // and the next sync() appends them to the old file instead.
+    pub fn rewrite(&mut self, records: &[(ClassId, Event)]) -> io::Result<()> {
+        self.buf = records
+            .iter()
+            .flat_map(|(class, event)| encode(*class, event))
+            .collect();
+
+        let tmp = self.path.with_extension("tmp");
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp)?;
+        file.write_all(&self.buf)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+        self.file = file;
+        self.buf.clear();
+
+        // Make the rename itself durable.
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        File::open(dir)?.sync_all()
+    }
+}
+
+//----------------------------------------------------------------
+
+// The shape of a tree, rebuilt by replaying a journal.  Holders and free
+// counts aren't journalled; after a crash there are no holders, and free
+// counts come from the space map.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TreeImage {
+    pub begin: u64,
+    pub end: u64,
+
+    // begin -> (end, cursor)
+    pub extents: BTreeMap<u64, (u64, u64)>,
+
+    // Bad blocks outlive snapshots.
+    pub bad: RangeSet,
+}
+
+impl TreeImage {
+    pub fn apply(&mut self, event: &Event) {
+        match event {
+            Event::Snapshot {
+                begin,
+                end,
+                extents,
+            } => {
+                self.begin = *begin;
+                self.end = *end;
+                self.extents = extents.iter().map(|(b, e, c)| (*b, (*e, *c))).collect();
+            }
+            Event::Split { begin, cut } => {
+                if let Some((end, cursor)) = self.extents.get(begin).copied() {
+                    self.extents.insert(*begin, (*cut, cursor.min(*cut)));
+                    self.extents.insert(*cut, (end, cursor.max(*cut)));
+                }
+            }
+            Event::Advance { begin, cursor } => {
+                if let Some((_, c)) = self.extents.get_mut(begin) {
+                    *c = *cursor;
+                }
+            }
+            Event::Release { begin } => {
+                // Full extents are pruned when released.
+                if let Some((end, cursor)) = self.extents.get(begin) {
+                    if cursor == end {
+                        self.extents.remove(begin);
+                    }
+                }
+            }
+            Event::Bad { begin, end } => {
+                self.bad.insert(*begin, *end);
+            }
+            Event::Borrow { .. } | Event::Free { .. } => {}
+        }
+    }
+}
+
+// Replays the records, starting from each class's most recent snapshot.
+// Classes that have no snapshot are missing from the result.
+pub fn replay(records: &[(ClassId, Event)]) -> BTreeMap<ClassId, TreeImage> {
+    let mut images: BTreeMap<ClassId, TreeImage> = BTreeMap::new();
+    for (class, event) in records {
+        if let Event::Snapshot { .. } = event {
+            images.entry(*class).or_default().apply(event);
+        } else if let Some(image) = images.get_mut(class) {
+            image.apply(event);
+        }
+    }
+    images
+}
+
+//----------------------------------------------------------------
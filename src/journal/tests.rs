@@ -0,0 +1,78 @@
+use anyhow::{ensure, Result};
+use std::os::unix::fs::FileExt;
+
+use crate::journal::*;
+
+//----------------------------------------------------------------
+
+#[test]
+fn journal_round_trip() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("journal");
+
+    let events = vec![
+        Event::Snapshot {
+            begin: 0,
+            end: 1000,
+            extents: vec![(0, 1000, 0)],
+        },
+        Event::Borrow { begin: 0 },
+        Event::Split { begin: 0, cut: 500 },
+        Event::Advance {
+            begin: 500,
+            cursor: 510,
+        },
+        Event::Free { b: 505 },
+        Event::Release { begin: 500 },
+    ];
+
+    let mut journal = Journal::create(&path)?;
+    for event in &events {
+        journal.append(1, event);
+    }
+    journal.sync()?;
+
+    let records = Journal::read(&path)?;
+    ensure!(records.iter().map(|(_, e)| e.clone()).collect::<Vec<_>>() == events);
+    ensure!(records.iter().all(|(class, _)| *class == 1));
+
+    let images = replay(&records);
+    let image = &images[&1];
+    ensure!(image.extents.len() == 2);
+    ensure!(image.extents[&0] == (500, 0));
+    ensure!(image.extents[&500] == (1000, 510));
+
+    Ok(())
+}
+
+#[test]
+fn journal_drops_torn_tail() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("journal");
+
+    let mut journal = Journal::create(&path)?;
+    for cursor in 1..=10 {
+        journal.append(0, &Event::Advance { begin: 0, cursor });
+    }
+    journal.sync()?;
+    drop(journal);
+
+    // corrupt the last record
+    let len = std::fs::metadata(&path)?.len();
+    let file = std::fs::OpenOptions::new().write(true).open(&path)?;
+    file.write_all_at(&[0xff], len - 1)?;
+
+    let (mut journal, records) = Journal::open(&path)?;
+    ensure!(records.len() == 9);
+
+    // new records follow the good prefix
+    journal.append(0, &Event::Release { begin: 0 });
+    journal.sync()?;
+    let records = Journal::read(&path)?;
+    ensure!(records.len() == 10);
+    ensure!(records[9].1 == Event::Release { begin: 0 });
+
+    Ok(())
+}
+
+//----------------------------------------------------------------
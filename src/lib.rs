@@ -1,2 +1,11 @@
 pub mod allocator;
+pub mod discard;
+pub mod journal;
+pub mod range_set;
+pub mod sharded;
+pub mod space_map;
+pub mod tiered;
 pub mod tree;
+
+#[cfg(test)]
+mod test_utils;
@@ -0,0 +1,124 @@
+use std::collections::BTreeMap;
+
+#[cfg(test)]
+mod tests;
+
+//----------------------------------------------------------------
+
+// A set of disjoint, coalesced (begin, end) block ranges.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RangeSet {
+    ranges: BTreeMap<u64, u64>,
+}
+
+impl RangeSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    // The total number of blocks covered.
+    pub fn nr_blocks(&self) -> u64 {
+        self.ranges.iter().map(|(b, e)| e - b).sum()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.ranges.iter().map(|(b, e)| (*b, *e))
+    }
+
+    // The range that contains block b, if any.
+    fn containing(&self, b: u64) -> Option<(u64, u64)> {
+        self.ranges
+            .range(..=b)
+            .next_back()
+            .filter(|(_, e)| **e > b)
+            .map(|(b, e)| (*b, *e))
+    }
+
+    pub fn contains(&self, b: u64) -> bool {
+        self.containing(b).is_some()
+    }
+
+    pub fn intersects(&self, begin: u64, end: u64) -> bool {
+        match self.first_gap(begin, end) {
+            Some((b, e)) => b != begin || e != end,
+            None => begin < end,
+        }
+    }
+
+    pub fn insert(&mut self, mut begin: u64, mut end: u64) {
+        if begin >= end {
+            return;
+        }
+
+        // merge with a predecessor that overlaps, or abuts, the new range
+        if let Some((b, e)) = self.ranges.range(..=begin).next_back() {
+            if *e >= begin {
+                begin = *b;
+                end = end.max(*e);
+            }
+        }
+
+        let covered: Vec<(u64, u64)> = self
+            .ranges
+            .range(begin..=end)
+            .map(|(b, e)| (*b, *e))
+            .collect();
+        for (b, e) in covered {
+            self.ranges.remove(&b);
+            end = end.max(e);
+        }
+
+        self.ranges.insert(begin, end);
+    }
+
+    pub fn remove(&mut self, begin: u64, end: u64) {
+        if begin >= end {
+            return;
+        }
+
+        let mut overlapping: Vec<(u64, u64)> = self
+            .ranges
+            .range(begin..end)
+            .map(|(b, e)| (*b, *e))
+            .collect();
+        if let Some((b, e)) = self.containing(begin) {
+            if b < begin {
+                overlapping.push((b, e));
+            }
+        }
+
+        for (b, e) in overlapping {
+            self.ranges.remove(&b);
+            if b < begin {
+                self.ranges.insert(b, begin);
+            }
+            if e > end {
+                self.ranges.insert(end, e);
+            }
+        }
+    }
+
+    // Returns the first sub-range of begin..end that isn't in the set.
+    pub fn first_gap(&self, begin: u64, end: u64) -> Option<(u64, u64)> {
+        let mut cursor = begin;
+        while cursor < end {
+            if let Some((_, e)) = self.containing(cursor) {
+                cursor = e;
+                continue;
+            }
+
+            let gap_end = match self.ranges.range(cursor..).next() {
+                Some((b, _)) => (*b).min(end),
+                None => end,
+            };
+            return Some((cursor, gap_end));
+        }
+        None
+    }
+}
+
+//----------------------------------------------------------------
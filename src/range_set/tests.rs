@@ -0,0 +1,55 @@
+use anyhow::{ensure, Result};
+
+use crate::range_set::*;
+
+//----------------------------------------------------------------
+
+#[test]
+fn insert_coalesces() -> Result<()> {
+    let mut set = RangeSet::new();
+    set.insert(10, 20);
+    set.insert(30, 40);
+    set.insert(20, 25);
+    ensure!(set.iter().collect::<Vec<_>>() == vec![(10, 25), (30, 40)]);
+
+    set.insert(5, 35);
+    ensure!(set.iter().collect::<Vec<_>>() == vec![(5, 40)]);
+    ensure!(set.nr_blocks() == 35);
+
+    Ok(())
+}
+
+#[test]
+fn remove_splits() -> Result<()> {
+    let mut set = RangeSet::new();
+    set.insert(0, 100);
+    set.remove(10, 20);
+    set.remove(90, 200);
+    ensure!(set.iter().collect::<Vec<_>>() == vec![(0, 10), (20, 90)]);
+
+    set.remove(0, 50);
+    ensure!(set.iter().collect::<Vec<_>>() == vec![(50, 90)]);
+
+    Ok(())
+}
+
+#[test]
+fn first_gap() -> Result<()> {
+    let mut set = RangeSet::new();
+    set.insert(10, 20);
+    set.insert(30, 40);
+
+    ensure!(set.first_gap(0, 100) == Some((0, 10)));
+    ensure!(set.first_gap(10, 100) == Some((20, 30)));
+    ensure!(set.first_gap(15, 35) == Some((20, 30)));
+    ensure!(set.first_gap(30, 40).is_none());
+    ensure!(set.first_gap(35, 50) == Some((40, 50)));
+
+    ensure!(set.intersects(15, 16));
+    ensure!(set.intersects(0, 11));
+    ensure!(!set.intersects(20, 30));
+
+    Ok(())
+}
+
+//----------------------------------------------------------------
@@ -0,0 +1,144 @@
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use crate::allocator::*;
+
+#[cfg(test)]
+mod tests;
+
+//----------------------------------------------------------------
+
+// A context in a sharded allocator.  It has a home shard, but keeps a
+// context in any other shard it's had to steal from.
+pub struct ShardedContext {
+    home: usize,
+    current: usize,
+    contexts: Vec<Option<ContextGuard>>,
+}
+
+impl ShardedContext {
+    pub fn home(&self) -> usize {
+        self.home
+    }
+}
+
+// Splits the blocks into a number of shards, each with its own tree and
+// lock, so threads working in different shards don't contend.
+pub struct ShardedAllocator {
+    shards: Vec<Mutex<Allocator>>,
+    next_shard: AtomicUsize,
+}
+
+impl ShardedAllocator {
+    pub fn new(nr_blocks: u64, nr_shards: usize, nr_nodes: u8) -> Self {
+        assert!(nr_shards > 0);
+
+        let nr_shards_ = nr_shards as u64;
+        let shards = (0..nr_shards_)
+            .map(|i| {
+                let begin = nr_blocks * i / nr_shards_;
+                let end = nr_blocks * (i + 1) / nr_shards_;
+                Mutex::new(Allocator::with_classes(&[ClassConfig::new(
+                    begin, end, nr_nodes,
+                )]))
+            })
+            .collect();
+
+        Self {
+            shards,
+            next_shard: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn nr_shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    // Assigns the context to the shards round-robin.
+    pub fn get_context(&self) -> ShardedContext {
+        let home = self.next_shard.fetch_add(1, Ordering::Relaxed) % self.shards.len();
+        self.get_context_in(home)
+    }
+
+    // Contexts that pass the same hint share a home shard.
+    pub fn get_context_hint(&self, hint: u64) -> ShardedContext {
+        self.get_context_in((hint % self.shards.len() as u64) as usize)
+    }
+
+    fn get_context_in(&self, home: usize) -> ShardedContext {
+        let mut contexts: Vec<Option<ContextGuard>> =
+            (0..self.shards.len()).map(|_| None).collect();
+        contexts[home] = Some(self.shards[home].lock().unwrap().get_context());
+
+        ShardedContext {
+            home,
+            current: home,
+            contexts,
+        }
+    }
+
+    pub fn put_context(&self, context: ShardedContext) {
+        for (shard, ctx) in context.contexts.into_iter().enumerate() {
+            if let Some(ctx) = ctx {
+                self.shards[shard].lock().unwrap().put_context(ctx);
+            }
+        }
+    }
+
+    fn alloc_in<F>(
+        &self,
+        context: &mut ShardedContext,
+        shard: usize,
+        f: &mut F,
+    ) -> io::Result<Option<u64>>
+    where
+        F: FnMut(u64, u64) -> io::Result<Option<u64>>,
+    {
+        let mut allocator = self.shards[shard].lock().unwrap();
+        let ctx = context.contexts[shard].get_or_insert_with(|| allocator.get_context());
+        allocator.alloc(ctx, &mut *f)
+    }
+
+    // Allocates from the shard the context last used.  If that's out of
+    // space we start again at the home shard, and then steal from its
+    // neighbours.
+    pub fn alloc<F>(&self, context: &mut ShardedContext, mut f: F) -> io::Result<Option<u64>>
+    where
+        F: FnMut(u64, u64) -> io::Result<Option<u64>>,
+    {
+        if let Some(b) = self.alloc_in(context, context.current, &mut f)? {
+            return Ok(Some(b));
+        }
+
+        let nr_shards = self.shards.len();
+        for i in 0..nr_shards {
+            let shard = (context.home + i) % nr_shards;
+            if shard == context.current {
+                continue;
+            }
+
+            if let Some(b) = self.alloc_in(context, shard, &mut f)? {
+                context.current = shard;
+                return Ok(Some(b));
+            }
+        }
+
+        Ok(None) // -ENOSPC
+    }
+
+    pub fn nr_free_blocks(&self) -> u64 {
+        self.shards
+            .iter()
+            .map(|s| s.lock().unwrap().nr_free_blocks())
+            .sum()
+    }
+
+    pub fn reset(&self) {
+        for shard in &self.shards {
+            shard.lock().unwrap().reset();
+        }
+    }
+}
+
+//----------------------------------------------------------------
@@ -0,0 +1,68 @@
+use anyhow::{ensure, Result};
+use roaring::RoaringBitmap;
+use std::thread;
+
+use crate::sharded::*;
+use crate::test_utils::*;
+
+//----------------------------------------------------------------
+
+#[test]
+fn contexts_allocate_from_home_shard() -> Result<()> {
+    let nr_blocks = 1024;
+    let allocator = ShardedAllocator::new(nr_blocks, 4, 15);
+    let allocated = Mutex::new(RoaringBitmap::new());
+
+    let mut context = allocator.get_context_hint(2);
+    ensure!(context.home() == 2);
+
+    for _ in 0..16 {
+        let b = allocator.alloc(&mut context, |b, e| {
+            alloc_block(&mut allocated.lock().unwrap(), b, e)
+        })?;
+        ensure!(matches!(b, Some(b) if (512..768).contains(&b)));
+    }
+    allocator.put_context(context);
+
+    Ok(())
+}
+
+#[test]
+fn steal_from_neighbours() -> Result<()> {
+    let nr_blocks = 1024;
+    let nr_threads = 4;
+    let allocator = ShardedAllocator::new(nr_blocks, nr_threads, 15);
+    let allocated = Mutex::new(RoaringBitmap::new());
+
+    // One thread allocates far more than its shard holds.
+    let counts: Vec<u64> = thread::scope(|s| {
+        let handles: Vec<_> = (0..nr_threads)
+            .map(|i| {
+                let allocator = &allocator;
+                let allocated = &allocated;
+                s.spawn(move || {
+                    let mut context = allocator.get_context();
+                    let n = if i == 0 { 512 } else { 64 };
+                    let mut count = 0;
+                    for _ in 0..n {
+                        if let Ok(Some(_)) = allocator.alloc(&mut context, |b, e| {
+                            alloc_block(&mut allocated.lock().unwrap(), b, e)
+                        }) {
+                            count += 1;
+                        }
+                    }
+                    allocator.put_context(context);
+                    count
+                })
+            })
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    });
+
+    ensure!(counts.iter().sum::<u64>() == 512 + 3 * 64);
+    ensure!(allocated.lock().unwrap().len() == 512 + 3 * 64);
+
+    Ok(())
+}
+
+//----------------------------------------------------------------
@@ -0,0 +1,159 @@
+use std::io;
+
+pub mod disk;
+pub mod mmap;
+pub mod ref_count;
+pub mod run_index;
+
+#[cfg(test)]
+mod tests;
+
+//----------------------------------------------------------------
+
+// Records which blocks are in use.  The allocator's tree only tracks
+// how far each extent's cursor has advanced; the space map is the
+// authority on what's actually free.
+pub trait SpaceMap {
+    fn nr_blocks(&self) -> u64;
+    fn nr_free(&self) -> u64;
+    fn is_free(&self, b: u64) -> bool;
+
+    // Returns the first free block in begin..end
+    fn find_free(&self, begin: u64, end: u64) -> Option<u64>;
+
+    fn mark_allocated(&mut self, b: u64) -> io::Result<()>;
+    fn mark_free(&mut self, b: u64) -> io::Result<()>;
+
+    // Drops a reference to block b, returning true if it's now free.
+    // Space maps without reference counts just free the block.
+    fn dec_ref(&mut self, b: u64) -> io::Result<bool> {
+        self.mark_free(b)?;
+        Ok(true)
+    }
+
+    fn count_free(&self, begin: u64, end: u64) -> u64 {
+        (begin..end).filter(|b| self.is_free(*b)).count() as u64
+    }
+
+    // Returns the first run of len free blocks in begin..end that starts
+    // on a multiple of align.
+    fn find_run(&self, begin: u64, end: u64, len: u64, align: u64) -> Option<u64> {
+        let mut b = begin.div_ceil(align) * align;
+        while b + len <= end {
+            if self.count_free(b, b + len) == len {
+                return Some(b);
+            }
+            b += align;
+        }
+        None
+    }
+
+    // Allocates the first free block in begin..end.  This has the
+    // signature the allocator's search callback expects.
+    fn alloc_in(&mut self, begin: u64, end: u64) -> io::Result<Option<u64>> {
+        match self.find_free(begin, end) {
+            Some(b) => {
+                self.mark_allocated(b)?;
+                Ok(Some(b))
+            }
+            None => Ok(None),
+        }
+    }
+
+    // As alloc_in(), but for an aligned run of blocks, suitable for
+    // Allocator::alloc_aligned().
+    fn alloc_run_in(
+        &mut self,
+        begin: u64,
+        end: u64,
+        len: u64,
+        align: u64,
+    ) -> io::Result<Option<u64>> {
+        match self.find_run(begin, end, len, align) {
+            Some(b) => {
+                for block in b..(b + len) {
+                    self.mark_allocated(block)?;
+                }
+                Ok(Some(b))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+//----------------------------------------------------------------
+
+// An in core space map with a bit per block.
+#[derive(Clone, Debug)]
+pub struct BitmapSpaceMap {
+    nr_blocks: u64,
+    nr_free: u64,
+    words: Vec<u64>,
+}
+
+impl BitmapSpaceMap {
+    pub fn new(nr_blocks: u64) -> Self {
+        Self {
+            nr_blocks,
+            nr_free: nr_blocks,
+            words: vec![0; nr_blocks.div_ceil(64) as usize],
+        }
+    }
+
+    pub fn from_allocated<I>(nr_blocks: u64, allocated: I) -> io::Result<Self>
+    where
+        I: IntoIterator<Item = u64>,
+    {
+        let mut sm = Self::new(nr_blocks);
+        for b in allocated {
+            sm.mark_allocated(b)?;
+        }
+        Ok(sm)
+    }
+}
+
+impl SpaceMap for BitmapSpaceMap {
+    fn nr_blocks(&self) -> u64 {
+        self.nr_blocks
+    }
+
+    fn nr_free(&self) -> u64 {
+        self.nr_free
+    }
+
+    fn is_free(&self, b: u64) -> bool {
+        assert!(b < self.nr_blocks);
+        self.words[(b / 64) as usize] & (1 << (b % 64)) == 0
+    }
+
+    fn find_free(&self, begin: u64, end: u64) -> Option<u64> {
+        let end = end.min(self.nr_blocks);
+        let mut b = begin;
+        while b < end {
+            // mask off the bits below b, and look for a clear one
+            let word = self.words[(b / 64) as usize] | ((1 << (b % 64)) - 1);
+            if word != u64::MAX {
+                let found = (b & !63) + word.trailing_ones() as u64;
+                return if found < end { Some(found) } else { None };
+            }
+            b = (b & !63) + 64;
+        }
+        None
+    }
+
+    fn mark_allocated(&mut self, b: u64) -> io::Result<()> {
+        assert!(self.is_free(b));
+        self.words[(b / 64) as usize] |= 1 << (b % 64);
+        self.nr_free -= 1;
+        Ok(())
+    }
+
+    fn mark_free(&mut self, b: u64) -> io::Result<()> {
+        assert!(!self.is_free(b));
+        self.words[(b / 64) as usize] &= !(1 << (b % 64));
+        self.nr_free += 1;
+        Ok(())
+    }
+}
+
+//----------------------------------------------------------------
@@ -0,0 +1,379 @@
+use std::collections::BTreeSet;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+use crate::space_map::SpaceMap;
+
+//----------------------------------------------------------------
+
+// A persistent space map laid out in the style of device-mapper's
+// persistent-data: a superblock, index blocks holding a free count per
+// bitmap, then fixed size bitmap blocks.  Every block starts with a
+// crc32 of the rest of the block, followed by the block's own location
+// so misdirected writes are caught.
+//
+// The whole map is held in core, flush() commits the changes.  Nothing
+// that's part of the last commit is written over: there are two copies
+// of the index, and two slots for each bitmap, with the index recording
+// which slot is live.  flush() writes the changed bitmaps to their spare
+// slots and the whole index to the spare copy, syncs, then writes a new
+// superblock naming that copy.  The superblock alternates between two
+// locations, with a sequence number, and open() uses the newest one that
+// checks out.  So a crash at any point leaves either the old or the new
+// commit intact, and anything that doesn't check out is corruption.
+
+pub const BLOCK_SIZE: usize = 4096;
+const HEADER_SIZE: usize = 16;
+const WORDS_PER_BITMAP: usize = (BLOCK_SIZE - HEADER_SIZE) / 8;
+pub const ENTRIES_PER_BITMAP: u64 = WORDS_PER_BITMAP as u64 * 64;
+const ENTRIES_PER_INDEX: usize = (BLOCK_SIZE - HEADER_SIZE) / 4;
+
+// The top bit of an index entry is the bitmap's live slot.
+const SLOT_BIT: u32 = 1 << 31;
+
+const MAGIC: u64 = 0x6273_705f_736d_3031;
+const VERSION: u32 = 2;
+const NR_SUPERBLOCKS: u64 = 2;
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn seal(buf: &mut [u8], loc: u64) {
+    buf[8..16].copy_from_slice(&loc.to_le_bytes());
+    let csum = crc32fast::hash(&buf[4..]);
+    buf[0..4].copy_from_slice(&csum.to_le_bytes());
+}
+
+fn check(buf: &[u8], loc: u64) -> io::Result<()> {
+    let csum = u32::from_le_bytes(buf[0..4].try_into().unwrap());
+    if csum != crc32fast::hash(&buf[4..]) {
+        return Err(invalid_data("bad checksum"));
+    }
+    if u64::from_le_bytes(buf[8..16].try_into().unwrap()) != loc {
+        return Err(invalid_data("block written to the wrong location"));
+    }
+    Ok(())
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn superblock_loc(seq: u64) -> u64 {
+    (seq % NR_SUPERBLOCKS) * BLOCK_SIZE as u64
+}
+
+//----------------------------------------------------------------
+
+pub struct DiskSpaceMap {
+    file: File,
+    nr_blocks: u64,
+    nr_free: u64,
+
+    // Free blocks in each bitmap
+    index: Vec<u32>,
+    words: Vec<u64>,
+
+    // The last commit: its sequence number, which copy of the index it
+    // wrote, and the live slot of each bitmap.
+    seq: u64,
+    index_copy: u64,
+    slots: Vec<u64>,
+
+    dirty: BTreeSet<usize>,
+    index_dirty: bool,
+}
+
+impl DiskSpaceMap {
+    // Creates a new file describing nr_blocks free blocks.
+    pub fn create<P: AsRef<Path>>(path: P, nr_blocks: u64) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+
+        // The first commit writes index copy 0, and slot 0 of every
+        // bitmap.
+        let nr_bitmaps = nr_blocks.div_ceil(ENTRIES_PER_BITMAP) as usize;
+        let mut sm = Self {
+            file,
+            nr_blocks,
+            nr_free: nr_blocks,
+            index: vec![ENTRIES_PER_BITMAP as u32; nr_bitmaps],
+            words: vec![0; nr_bitmaps * WORDS_PER_BITMAP],
+            seq: 0,
+            index_copy: 1,
+            slots: vec![1; nr_bitmaps],
+            dirty: (0..nr_bitmaps).collect(),
+            index_dirty: true,
+        };
+        sm.file.set_len(sm.bitmap_loc(nr_bitmaps, 0))?;
+
+        // The tail of the last bitmap is permanently allocated.
+        for b in nr_blocks..(nr_bitmaps as u64 * ENTRIES_PER_BITMAP) {
+            sm.words[(b / 64) as usize] |= 1 << (b % 64);
+        }
+        if let Some(last) = sm.index.last_mut() {
+            *last -= (nr_bitmaps as u64 * ENTRIES_PER_BITMAP - nr_blocks) as u32;
+        }
+
+        sm.flush()?;
+        Ok(sm)
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+
+        // Use the newest superblock that checks out.  The other is either
+        // the previous commit, or a torn write of the next.
+        let mut buf = vec![0; BLOCK_SIZE];
+        let mut newest: Option<(u64, u64, u64)> = None;
+        for i in 0..NR_SUPERBLOCKS {
+            let loc = i * BLOCK_SIZE as u64;
+            file.read_exact_at(&mut buf, loc)?;
+            if check(&buf, loc).is_err() || read_u64(&buf, 16) != MAGIC {
+                continue;
+            }
+            let version = read_u32(&buf, 24);
+            let block_size = read_u32(&buf, 28);
+            if version != VERSION || block_size as usize != BLOCK_SIZE {
+                return Err(invalid_data("unsupported space map format"));
+            }
+
+            let seq = read_u64(&buf, 40);
+            if loc == superblock_loc(seq) && newest.is_none_or(|(s, _, _)| seq > s) {
+                newest = Some((seq, read_u64(&buf, 32), read_u64(&buf, 48)));
+            }
+        }
+        let Some((seq, nr_blocks, index_copy)) = newest else {
+            return Err(invalid_data("not a space map"));
+        };
+
+        let nr_bitmaps = nr_blocks.div_ceil(ENTRIES_PER_BITMAP) as usize;
+        let mut sm = Self {
+            file,
+            nr_blocks,
+            nr_free: 0,
+            index: Vec::with_capacity(nr_bitmaps),
+            words: Vec::with_capacity(nr_bitmaps * WORDS_PER_BITMAP),
+            seq,
+            index_copy,
+            slots: Vec::with_capacity(nr_bitmaps),
+            dirty: BTreeSet::new(),
+            index_dirty: false,
+        };
+
+        for i in 0..sm.nr_index_blocks() {
+            let loc = sm.index_loc(index_copy, i);
+            sm.file.read_exact_at(&mut buf, loc)?;
+            check(&buf, loc)?;
+            let entries = (nr_bitmaps - sm.index.len()).min(ENTRIES_PER_INDEX);
+            for e in 0..entries {
+                let entry = read_u32(&buf, HEADER_SIZE + e * 4);
+                sm.index.push(entry & !SLOT_BIT);
+                sm.slots.push((entry & SLOT_BIT != 0) as u64);
+            }
+        }
+
+        for i in 0..nr_bitmaps {
+            let loc = sm.bitmap_loc(i, sm.slots[i]);
+            sm.file.read_exact_at(&mut buf, loc)?;
+            check(&buf, loc)?;
+            let mut nr_allocated = 0;
+            for w in 0..WORDS_PER_BITMAP {
+                let word = read_u64(&buf, HEADER_SIZE + w * 8);
+                nr_allocated += word.count_ones();
+                sm.words.push(word);
+            }
+
+            // Both were written by the same commit.
+            if ENTRIES_PER_BITMAP as u32 - nr_allocated != sm.index[i] {
+                return Err(invalid_data("index doesn't match bitmap"));
+            }
+        }
+
+        sm.nr_free = sm.index.iter().map(|n| *n as u64).sum();
+        Ok(sm)
+    }
+
+    pub fn nr_bitmaps(&self) -> usize {
+        self.index.len()
+    }
+
+    // Free blocks in the i'th bitmap, from the index.
+    pub fn bitmap_free(&self, i: usize) -> u64 {
+        self.index[i] as u64
+    }
+
+    fn nr_index_blocks(&self) -> usize {
+        self.nr_blocks
+            .div_ceil(ENTRIES_PER_BITMAP)
+            .div_ceil(ENTRIES_PER_INDEX as u64) as usize
+    }
+
+    fn index_loc(&self, copy: u64, i: usize) -> u64 {
+        let block = NR_SUPERBLOCKS + copy * self.nr_index_blocks() as u64 + i as u64;
+        block * BLOCK_SIZE as u64
+    }
+
+    fn bitmap_loc(&self, i: usize, slot: u64) -> u64 {
+        let block = NR_SUPERBLOCKS + 2 * self.nr_index_blocks() as u64 + 2 * i as u64 + slot;
+        block * BLOCK_SIZE as u64
+    }
+
+    fn write_superblock(&self, seq: u64, index_copy: u64) -> io::Result<()> {
+        let mut buf = vec![0; BLOCK_SIZE];
+        buf[16..24].copy_from_slice(&MAGIC.to_le_bytes());
+        buf[24..28].copy_from_slice(&VERSION.to_le_bytes());
+        buf[28..32].copy_from_slice(&(BLOCK_SIZE as u32).to_le_bytes());
+        buf[32..40].copy_from_slice(&self.nr_blocks.to_le_bytes());
+        buf[40..48].copy_from_slice(&seq.to_le_bytes());
+        buf[48..56].copy_from_slice(&index_copy.to_le_bytes());
+        let loc = superblock_loc(seq);
+        seal(&mut buf, loc);
+        self.file.write_all_at(&buf, loc)
+    }
+
+    // Commits everything that's changed since the last flush.
+    pub fn flush(&mut self) -> io::Result<()> {
+        if !self.index_dirty {
+            return self.file.sync_all();
+        }
+
+        let mut buf = vec![0; BLOCK_SIZE];
+        let mut slots = self.slots.clone();
+        for &i in &self.dirty {
+            buf.fill(0);
+            let words = &self.words[i * WORDS_PER_BITMAP..(i + 1) * WORDS_PER_BITMAP];
+            for (w, word) in words.iter().enumerate() {
+                let offset = HEADER_SIZE + w * 8;
+                buf[offset..offset + 8].copy_from_slice(&word.to_le_bytes());
+            }
+            slots[i] = 1 - self.slots[i];
+            let loc = self.bitmap_loc(i, slots[i]);
+            seal(&mut buf, loc);
+            self.file.write_all_at(&buf, loc)?;
+        }
+
+        let index_copy = 1 - self.index_copy;
+        for (i, entries) in self.index.chunks(ENTRIES_PER_INDEX).enumerate() {
+            buf.fill(0);
+            for (e, nr_free) in entries.iter().enumerate() {
+                let entry = nr_free | (slots[i * ENTRIES_PER_INDEX + e] as u32 * SLOT_BIT);
+                let offset = HEADER_SIZE + e * 4;
+                buf[offset..offset + 4].copy_from_slice(&entry.to_le_bytes());
+            }
+            let loc = self.index_loc(index_copy, i);
+            seal(&mut buf, loc);
+            self.file.write_all_at(&buf, loc)?;
+        }
+        self.file.sync_all()?;
+
+        // The commit point.
+        self.write_superblock(self.seq + 1, index_copy)?;
+        self.file.sync_all()?;
+
+        self.seq += 1;
+        self.index_copy = index_copy;
+        self.slots = slots;
+        self.dirty.clear();
+        self.index_dirty = false;
+        Ok(())
+    }
+
+    fn set(&mut self, b: u64, allocated: bool) {
+        let bitmap = (b / ENTRIES_PER_BITMAP) as usize;
+        let word = &mut self.words[(b / 64) as usize];
+        if allocated {
+            *word |= 1 << (b % 64);
+            self.index[bitmap] -= 1;
+            self.nr_free -= 1;
+        } else {
+            *word &= !(1 << (b % 64));
+            self.index[bitmap] += 1;
+            self.nr_free += 1;
+        }
+        self.dirty.insert(bitmap);
+        self.index_dirty = true;
+    }
+}
+
+impl SpaceMap for DiskSpaceMap {
+    fn nr_blocks(&self) -> u64 {
+        self.nr_blocks
+    }
+
+    fn nr_free(&self) -> u64 {
+        self.nr_free
+    }
+
+    fn is_free(&self, b: u64) -> bool {
+        assert!(b < self.nr_blocks);
+        self.words[(b / 64) as usize] & (1 << (b % 64)) == 0
+    }
+
+    fn find_free(&self, begin: u64, end: u64) -> Option<u64> {
+        let end = end.min(self.nr_blocks);
+        let mut b = begin;
+        while b < end {
+            // skip bitmaps the index says are full
+            let bitmap = b / ENTRIES_PER_BITMAP;
+            if self.index[bitmap as usize] == 0 {
+                b = (bitmap + 1) * ENTRIES_PER_BITMAP;
+                continue;
+            }
+
+            let word = self.words[(b / 64) as usize] | ((1 << (b % 64)) - 1);
+            if word != u64::MAX {
+                let found = (b & !63) + word.trailing_ones() as u64;
+                return if found < end { Some(found) } else { None };
+            }
+            b = (b & !63) + 64;
+        }
+        None
+    }
+
+    // Whole bitmaps are counted from the index, so seeding and scoring
+    // the tree doesn't walk the bits of large regions.
+    fn count_free(&self, begin: u64, end: u64) -> u64 {
+        let end = end.min(self.nr_blocks);
+        let mut nr_free = 0;
+        let mut b = begin;
+        while b < end {
+            let bitmap = b / ENTRIES_PER_BITMAP;
+            let bitmap_end = (bitmap + 1) * ENTRIES_PER_BITMAP;
+            if b == bitmap * ENTRIES_PER_BITMAP && bitmap_end <= end {
+                nr_free += self.index[bitmap as usize] as u64;
+            } else {
+                nr_free += (b..bitmap_end.min(end))
+                    .filter(|b| self.is_free(*b))
+                    .count() as u64;
+            }
+            b = bitmap_end;
+        }
+        nr_free
+    }
+
+    fn mark_allocated(&mut self, b: u64) -> io::Result<()> {
+        assert!(self.is_free(b));
+        self.set(b, true);
+        Ok(())
+    }
+
+    fn mark_free(&mut self, b: u64) -> io::Result<()> {
+        assert!(!self.is_free(b));
+        self.set(b, false);
+        Ok(())
+    }
+}
+
+//----------------------------------------------------------------
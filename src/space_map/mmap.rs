@@ -0,0 +1,234 @@
+use memmap2::MmapMut;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::Path;
+
+use crate::space_map::SpaceMap;
+
+//----------------------------------------------------------------
+
+// A bitmap space map kept in a memory mapped file, so large pools don't
+// need the bitmap on the heap.
+//
+// The first page holds two header slots, each with a sequence number and
+// checksum.  sync() flushes the bitmap, then writes the older slot, so a
+// torn header write always leaves the previous one intact.  Free counts
+// aren't trusted across a crash; they're recounted from the bitmap when
+// the file's opened.
+
+const HEADER_SIZE: u64 = 4096;
+const SLOT_SIZE: usize = 64;
+const MAGIC: u64 = 0x6273_705f_6d6d_3031;
+
+#[derive(Clone, Copy, Debug)]
+struct Header {
+    seq: u64,
+    nr_blocks: u64,
+}
+
+fn pack_header(header: &Header) -> [u8; SLOT_SIZE] {
+    let mut buf = [0; SLOT_SIZE];
+    buf[8..16].copy_from_slice(&MAGIC.to_le_bytes());
+    buf[16..24].copy_from_slice(&header.seq.to_le_bytes());
+    buf[24..32].copy_from_slice(&header.nr_blocks.to_le_bytes());
+    let csum = crc32fast::hash(&buf[4..]);
+    buf[0..4].copy_from_slice(&csum.to_le_bytes());
+    buf
+}
+
+fn unpack_header(buf: &[u8]) -> Option<Header> {
+    let csum = u32::from_le_bytes(buf[0..4].try_into().unwrap());
+    let magic = u64::from_le_bytes(buf[8..16].try_into().unwrap());
+    if csum != crc32fast::hash(&buf[4..SLOT_SIZE]) || magic != MAGIC {
+        return None;
+    }
+    Some(Header {
+        seq: u64::from_le_bytes(buf[16..24].try_into().unwrap()),
+        nr_blocks: u64::from_le_bytes(buf[24..32].try_into().unwrap()),
+    })
+}
+
+fn file_len(nr_blocks: u64) -> u64 {
+    HEADER_SIZE + nr_blocks.div_ceil(64) * 8
+}
+
+//----------------------------------------------------------------
+
+pub struct MmapSpaceMap {
+    file: File,
+    map: MmapMut,
+    seq: u64,
+    nr_blocks: u64,
+    nr_free: u64,
+}
+
+impl MmapSpaceMap {
+    pub fn create<P: AsRef<Path>>(path: P, nr_blocks: u64) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        file.set_len(file_len(nr_blocks))?;
+
+        let mut sm = Self {
+            map: Self::map(&file)?,
+            file,
+            seq: 0,
+            nr_blocks,
+            nr_free: nr_blocks,
+        };
+        sm.sync()?;
+        Ok(sm)
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let map = Self::map(&file)?;
+        if (map.len() as u64) < HEADER_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "space map header truncated",
+            ));
+        }
+
+        let header = (0..2)
+            .filter_map(|slot| unpack_header(&map[slot * SLOT_SIZE..(slot + 1) * SLOT_SIZE]))
+            .max_by_key(|h| h.seq)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no valid header"))?;
+
+        // A crash part way through a resize can leave the file longer
+        // than the header says, but never shorter.
+        if (map.len() as u64) < file_len(header.nr_blocks) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "space map bitmap truncated",
+            ));
+        }
+
+        let mut sm = Self {
+            file,
+            map,
+            seq: header.seq,
+            nr_blocks: header.nr_blocks,
+            nr_free: 0,
+        };
+        sm.nr_free = sm.nr_blocks - sm.count_allocated();
+        Ok(sm)
+    }
+
+    fn map(file: &File) -> io::Result<MmapMut> {
+        // The file is private to this space map, nothing else will
+        // truncate it under us.
+        unsafe { MmapMut::map_mut(file) }
+    }
+
+    fn word(&self, w: u64) -> u64 {
+        let offset = (HEADER_SIZE + w * 8) as usize;
+        u64::from_le_bytes(self.map[offset..offset + 8].try_into().unwrap())
+    }
+
+    fn set_word(&mut self, w: u64, word: u64) {
+        let offset = (HEADER_SIZE + w * 8) as usize;
+        self.map[offset..offset + 8].copy_from_slice(&word.to_le_bytes());
+    }
+
+    fn count_allocated(&self) -> u64 {
+        (0..self.nr_blocks.div_ceil(64))
+            .map(|w| self.word(w).count_ones() as u64)
+            .sum()
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        self.seq += 1;
+        let slot = (self.seq % 2) as usize * SLOT_SIZE;
+        let buf = pack_header(&Header {
+            seq: self.seq,
+            nr_blocks: self.nr_blocks,
+        });
+        self.map[slot..slot + SLOT_SIZE].copy_from_slice(&buf);
+        self.map.flush_range(0, HEADER_SIZE as usize)
+    }
+
+    // Makes the bitmap durable.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.map.flush()?;
+        self.write_header()
+    }
+
+    // Grows or shrinks the map.  Blocks added are free, blocks removed
+    // are forgotten, so evacuate them first.  Pair with
+    // Allocator::resize().
+    pub fn resize(&mut self, nr_blocks: u64) -> io::Result<()> {
+        if nr_blocks >= self.nr_blocks {
+            // The new bits are already zero, since bits past the end
+            // are kept clear and the file grows with zeroes.
+            self.map.flush()?;
+            self.file.set_len(file_len(nr_blocks))?;
+            self.map = Self::map(&self.file)?;
+            self.nr_free += nr_blocks - self.nr_blocks;
+            self.nr_blocks = nr_blocks;
+            self.write_header()
+        } else {
+            let nr_lost = self.count_free(nr_blocks, self.nr_blocks);
+            if !nr_blocks.is_multiple_of(64) {
+                let w = nr_blocks / 64;
+                let word = self.word(w) & ((1 << (nr_blocks % 64)) - 1);
+                self.set_word(w, word);
+            }
+            self.nr_free -= nr_lost;
+            self.nr_blocks = nr_blocks;
+            self.sync()?;
+            self.file.set_len(file_len(nr_blocks))?;
+            self.map = Self::map(&self.file)?;
+            Ok(())
+        }
+    }
+}
+
+impl SpaceMap for MmapSpaceMap {
+    fn nr_blocks(&self) -> u64 {
+        self.nr_blocks
+    }
+
+    fn nr_free(&self) -> u64 {
+        self.nr_free
+    }
+
+    fn is_free(&self, b: u64) -> bool {
+        assert!(b < self.nr_blocks);
+        self.word(b / 64) & (1 << (b % 64)) == 0
+    }
+
+    fn find_free(&self, begin: u64, end: u64) -> Option<u64> {
+        let end = end.min(self.nr_blocks);
+        let mut b = begin;
+        while b < end {
+            // mask off the bits below b, and look for a clear one
+            let word = self.word(b / 64) | ((1 << (b % 64)) - 1);
+            if word != u64::MAX {
+                let found = (b & !63) + word.trailing_ones() as u64;
+                return if found < end { Some(found) } else { None };
+            }
+            b = (b & !63) + 64;
+        }
+        None
+    }
+
+    fn mark_allocated(&mut self, b: u64) -> io::Result<()> {
+        assert!(self.is_free(b));
+        self.set_word(b / 64, self.word(b / 64) | (1 << (b % 64)));
+        self.nr_free -= 1;
+        Ok(())
+    }
+
+    fn mark_free(&mut self, b: u64) -> io::Result<()> {
+        assert!(!self.is_free(b));
+        self.set_word(b / 64, self.word(b / 64) & !(1 << (b % 64)));
+        self.nr_free += 1;
+        Ok(())
+    }
+}
+
+//----------------------------------------------------------------
@@ -0,0 +1,133 @@
+use std::collections::BTreeMap;
+use std::io;
+
+use crate::space_map::SpaceMap;
+
+//----------------------------------------------------------------
+
+const ENTRIES_PER_WORD: u64 = 32;
+const OVERFLOW: u64 = 3;
+
+// A space map for pools that share blocks, eg. between snapshots.  Each
+// block has a reference count; counts below 3 are held inline in two
+// bits, larger ones overflow into a side table.  A block is only free
+// when its count is zero.
+#[derive(Clone, Debug)]
+pub struct RefCountSpaceMap {
+    nr_blocks: u64,
+    nr_free: u64,
+    words: Vec<u64>,
+    overflow: BTreeMap<u64, u64>,
+}
+
+impl RefCountSpaceMap {
+    pub fn new(nr_blocks: u64) -> Self {
+        Self {
+            nr_blocks,
+            nr_free: nr_blocks,
+            words: vec![0; nr_blocks.div_ceil(ENTRIES_PER_WORD) as usize],
+            overflow: BTreeMap::new(),
+        }
+    }
+
+    fn inline(&self, b: u64) -> u64 {
+        let shift = 2 * (b % ENTRIES_PER_WORD);
+        (self.words[(b / ENTRIES_PER_WORD) as usize] >> shift) & 3
+    }
+
+    pub fn get_count(&self, b: u64) -> u64 {
+        assert!(b < self.nr_blocks);
+        match self.inline(b) {
+            OVERFLOW => self.overflow[&b],
+            count => count,
+        }
+    }
+
+    pub fn set_count(&mut self, b: u64, count: u64) {
+        let old = self.get_count(b);
+        match (old, count) {
+            (0, 1..) => self.nr_free -= 1,
+            (1.., 0) => self.nr_free += 1,
+            _ => {}
+        }
+
+        let inline = if count >= OVERFLOW {
+            self.overflow.insert(b, count);
+            OVERFLOW
+        } else {
+            self.overflow.remove(&b);
+            count
+        };
+
+        let shift = 2 * (b % ENTRIES_PER_WORD);
+        let word = &mut self.words[(b / ENTRIES_PER_WORD) as usize];
+        *word = (*word & !(3 << shift)) | (inline << shift);
+    }
+
+    // Adds a reference to an allocated block.
+    pub fn inc_ref(&mut self, b: u64) {
+        let count = self.get_count(b);
+        assert!(count > 0);
+        self.set_count(b, count + 1);
+    }
+
+    pub fn nr_overflowed(&self) -> usize {
+        self.overflow.len()
+    }
+}
+
+impl SpaceMap for RefCountSpaceMap {
+    fn nr_blocks(&self) -> u64 {
+        self.nr_blocks
+    }
+
+    fn nr_free(&self) -> u64 {
+        self.nr_free
+    }
+
+    fn is_free(&self, b: u64) -> bool {
+        assert!(b < self.nr_blocks);
+        self.inline(b) == 0
+    }
+
+    fn find_free(&self, begin: u64, end: u64) -> Option<u64> {
+        let end = end.min(self.nr_blocks);
+        let mut b = begin;
+        while b < end {
+            let word = self.words[(b / ENTRIES_PER_WORD) as usize];
+
+            // One bit set for each zero entry at or above b
+            let mut zeros = !(word | (word >> 1)) & 0x5555_5555_5555_5555;
+            zeros &= u64::MAX << (2 * (b % ENTRIES_PER_WORD));
+            if zeros != 0 {
+                let found =
+                    (b / ENTRIES_PER_WORD) * ENTRIES_PER_WORD + zeros.trailing_zeros() as u64 / 2;
+                return if found < end { Some(found) } else { None };
+            }
+            b = (b / ENTRIES_PER_WORD + 1) * ENTRIES_PER_WORD;
+        }
+        None
+    }
+
+    fn mark_allocated(&mut self, b: u64) -> io::Result<()> {
+        assert!(self.is_free(b));
+        self.set_count(b, 1);
+        Ok(())
+    }
+
+    // Frees the block, regardless of how many references it has.
+    fn mark_free(&mut self, b: u64) -> io::Result<()> {
+        assert!(!self.is_free(b));
+        self.set_count(b, 0);
+        Ok(())
+    }
+
+    fn dec_ref(&mut self, b: u64) -> io::Result<bool> {
+        let count = self.get_count(b);
+        assert!(count > 0);
+        self.set_count(b, count - 1);
+        Ok(count == 1)
+    }
+}
+
+//----------------------------------------------------------------
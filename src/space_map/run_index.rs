@@ -0,0 +1,149 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+
+use crate::space_map::SpaceMap;
+
+//----------------------------------------------------------------
+
+// An in core space map that indexes the free runs, both by where they
+// start and by their length.  Finding the next free block after a
+// cursor, and updating the map, are O(log n) in the number of runs.
+// Finding a run of a given length walks the runs in order from begin,
+// stopping at the first that fits, but gives up straight away if no run
+// is long enough.
+#[derive(Clone, Debug)]
+pub struct RunIndexSpaceMap {
+    nr_blocks: u64,
+    nr_free: u64,
+
+    // begin -> end
+    by_start: BTreeMap<u64, u64>,
+
+    // (len, begin)
+    by_len: BTreeSet<(u64, u64)>,
+}
+
+impl RunIndexSpaceMap {
+    pub fn new(nr_blocks: u64) -> Self {
+        let mut sm = Self {
+            nr_blocks,
+            nr_free: 0,
+            by_start: BTreeMap::new(),
+            by_len: BTreeSet::new(),
+        };
+        sm.insert_run(0, nr_blocks);
+        sm
+    }
+
+    pub fn nr_runs(&self) -> usize {
+        self.by_start.len()
+    }
+
+    // The length of the longest free run.
+    pub fn longest_run(&self) -> u64 {
+        self.by_len.last().map(|(len, _)| *len).unwrap_or(0)
+    }
+
+    fn insert_run(&mut self, begin: u64, end: u64) {
+        if begin < end {
+            self.by_start.insert(begin, end);
+            self.by_len.insert((end - begin, begin));
+            self.nr_free += end - begin;
+        }
+    }
+
+    fn remove_run(&mut self, begin: u64) -> u64 {
+        let end = self.by_start.remove(&begin).unwrap();
+        self.by_len.remove(&(end - begin, begin));
+        self.nr_free -= end - begin;
+        end
+    }
+
+    // The free run containing block b.
+    fn containing(&self, b: u64) -> Option<(u64, u64)> {
+        self.by_start
+            .range(..=b)
+            .next_back()
+            .filter(|(_, e)| **e > b)
+            .map(|(b, e)| (*b, *e))
+    }
+
+    // Free runs that intersect begin..end, clipped to it.
+    fn runs_in(&self, begin: u64, end: u64) -> impl Iterator<Item = (u64, u64)> + '_ {
+        let first = self
+            .containing(begin)
+            .filter(|_| begin < end)
+            .map(|(_, e)| (begin, e.min(end)));
+        let rest = self
+            .by_start
+            .range(begin..end)
+            .filter(move |(b, _)| **b != begin || first.is_none())
+            .map(move |(b, e)| (*b, (*e).min(end)));
+        first.into_iter().chain(rest)
+    }
+}
+
+impl SpaceMap for RunIndexSpaceMap {
+    fn nr_blocks(&self) -> u64 {
+        self.nr_blocks
+    }
+
+    fn nr_free(&self) -> u64 {
+        self.nr_free
+    }
+
+    fn is_free(&self, b: u64) -> bool {
+        self.containing(b).is_some()
+    }
+
+    fn find_free(&self, begin: u64, end: u64) -> Option<u64> {
+        self.runs_in(begin, end).next().map(|(b, _)| b)
+    }
+
+    fn count_free(&self, begin: u64, end: u64) -> u64 {
+        self.runs_in(begin, end).map(|(b, e)| e - b).sum()
+    }
+
+    fn find_run(&self, begin: u64, end: u64, len: u64, align: u64) -> Option<u64> {
+        if self.longest_run() < len {
+            return None;
+        }
+
+        self.runs_in(begin, end).find_map(|(b, e)| {
+            let aligned = b.div_ceil(align) * align;
+            (aligned + len <= e).then_some(aligned)
+        })
+    }
+
+    fn mark_allocated(&mut self, b: u64) -> io::Result<()> {
+        let (begin, _) = self.containing(b).expect("block already allocated");
+        let end = self.remove_run(begin);
+        self.insert_run(begin, b);
+        self.insert_run(b + 1, end);
+        Ok(())
+    }
+
+    fn mark_free(&mut self, b: u64) -> io::Result<()> {
+        assert!(b < self.nr_blocks);
+        assert!(!self.is_free(b));
+
+        let mut begin = b;
+        let mut end = b + 1;
+
+        // Merge with the neighbouring runs.
+        if b > 0 {
+            if let Some((pb, _)) = self.containing(b - 1) {
+                begin = pb;
+                self.remove_run(pb);
+            }
+        }
+        if self.by_start.contains_key(&end) {
+            end = self.remove_run(end);
+        }
+
+        self.insert_run(begin, end);
+        Ok(())
+    }
+}
+
+//----------------------------------------------------------------
@@ -0,0 +1,256 @@
+use anyhow::{ensure, Result};
+
+use crate::space_map::disk::*;
+use crate::space_map::mmap::*;
+use crate::space_map::ref_count::*;
+use crate::space_map::run_index::*;
+use crate::space_map::*;
+
+//----------------------------------------------------------------
+
+#[test]
+fn bitmap_find_free() -> Result<()> {
+    let nr_blocks = 200;
+    let mut sm = BitmapSpaceMap::new(nr_blocks);
+    for b in 0..150 {
+        sm.mark_allocated(b)?;
+    }
+    sm.mark_free(70)?;
+
+    ensure!(sm.nr_free() == 51);
+    ensure!(sm.find_free(0, nr_blocks) == Some(70));
+    ensure!(sm.find_free(71, nr_blocks) == Some(150));
+    ensure!(sm.find_free(71, 150).is_none());
+    ensure!(sm.count_free(0, 160) == 11);
+
+    ensure!(sm.alloc_in(0, nr_blocks)? == Some(70));
+    ensure!(!sm.is_free(70));
+
+    Ok(())
+}
+
+#[test]
+fn run_index_matches_bitmap() -> Result<()> {
+    let nr_blocks = 2000;
+    let mut bitmap = BitmapSpaceMap::new(nr_blocks);
+    let mut runs = RunIndexSpaceMap::new(nr_blocks);
+
+    for _ in 0..5000 {
+        let b = rand::random::<u64>() % nr_blocks;
+        if bitmap.is_free(b) {
+            bitmap.mark_allocated(b)?;
+            runs.mark_allocated(b)?;
+        } else {
+            bitmap.mark_free(b)?;
+            runs.mark_free(b)?;
+        }
+    }
+
+    ensure!(runs.nr_free() == bitmap.nr_free());
+    for _ in 0..100 {
+        let begin = rand::random::<u64>() % nr_blocks;
+        let end = begin + rand::random::<u64>() % (nr_blocks - begin);
+        ensure!(runs.find_free(begin, end) == bitmap.find_free(begin, end));
+        ensure!(runs.count_free(begin, end) == bitmap.count_free(begin, end));
+        ensure!(runs.find_run(begin, end, 3, 4) == bitmap.find_run(begin, end, 3, 4));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn run_index_coalesces() -> Result<()> {
+    let mut sm = RunIndexSpaceMap::new(100);
+    ensure!(sm.alloc_run_in(0, 100, 10, 8)? == Some(0));
+    ensure!(sm.alloc_run_in(0, 100, 10, 8)? == Some(16));
+    ensure!(sm.nr_runs() == 2);
+    ensure!(sm.longest_run() == 74);
+
+    for b in 0..10 {
+        sm.mark_free(b)?;
+    }
+    ensure!(sm.nr_runs() == 2);
+    ensure!(sm.find_free(0, 100) == Some(0));
+    ensure!(sm.find_run(0, 100, 75, 1).is_none());
+
+    Ok(())
+}
+
+#[test]
+fn ref_count_overflow() -> Result<()> {
+    let nr_blocks = 100;
+    let mut sm = RefCountSpaceMap::new(nr_blocks);
+
+    ensure!(sm.alloc_in(0, nr_blocks)? == Some(0));
+    for _ in 0..9 {
+        sm.inc_ref(0);
+    }
+    ensure!(sm.get_count(0) == 10);
+    ensure!(sm.nr_overflowed() == 1);
+    ensure!(sm.find_free(0, nr_blocks) == Some(1));
+
+    for _ in 0..9 {
+        ensure!(!sm.dec_ref(0)?);
+    }
+    ensure!(sm.nr_overflowed() == 0);
+    ensure!(sm.dec_ref(0)?);
+    ensure!(sm.is_free(0));
+    ensure!(sm.nr_free() == nr_blocks);
+
+    Ok(())
+}
+
+#[test]
+fn ref_count_find_free() -> Result<()> {
+    let nr_blocks = 200;
+    let mut sm = RefCountSpaceMap::new(nr_blocks);
+    for b in 0..150 {
+        sm.mark_allocated(b)?;
+    }
+    sm.set_count(40, 2);
+    sm.mark_free(70)?;
+
+    ensure!(sm.find_free(0, nr_blocks) == Some(70));
+    ensure!(sm.find_free(71, nr_blocks) == Some(150));
+    ensure!(sm.find_free(71, 150).is_none());
+    ensure!(sm.nr_free() == 51);
+
+    Ok(())
+}
+
+#[test]
+fn disk_space_map_persists() -> Result<()> {
+    let nr_blocks = 3 * ENTRIES_PER_BITMAP + 100;
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("space_map");
+
+    let mut sm = DiskSpaceMap::create(&path, nr_blocks)?;
+    ensure!(sm.nr_bitmaps() == 4);
+    for b in 0..ENTRIES_PER_BITMAP {
+        sm.mark_allocated(b)?;
+    }
+    sm.mark_allocated(nr_blocks - 1)?;
+    sm.mark_free(70)?;
+    sm.flush()?;
+    drop(sm);
+
+    let sm = DiskSpaceMap::open(&path)?;
+    ensure!(sm.nr_free() == nr_blocks - ENTRIES_PER_BITMAP);
+    ensure!(sm.bitmap_free(0) == 1);
+    ensure!(sm.bitmap_free(3) == 99);
+    ensure!(sm.find_free(0, nr_blocks) == Some(70));
+    ensure!(sm.find_free(71, nr_blocks) == Some(ENTRIES_PER_BITMAP));
+    ensure!(sm.count_free(0, nr_blocks) == sm.nr_free());
+    ensure!(sm.count_free(50, 2 * ENTRIES_PER_BITMAP + 10) == ENTRIES_PER_BITMAP + 11);
+
+    Ok(())
+}
+
+#[test]
+fn disk_space_map_detects_corruption() -> Result<()> {
+    use std::os::unix::fs::FileExt;
+
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("space_map");
+    DiskSpaceMap::create(&path, 1000)?;
+
+    // flip a bit in the bitmap, which follows two superblocks and two
+    // copies of the index
+    let file = std::fs::OpenOptions::new().write(true).open(&path)?;
+    file.write_all_at(&[1], 4 * BLOCK_SIZE as u64 + 100)?;
+
+    let err = DiskSpaceMap::open(&path).err();
+    ensure!(err.map(|e| e.kind()) == Some(std::io::ErrorKind::InvalidData));
+
+    Ok(())
+}
+
+#[test]
+fn disk_space_map_survives_torn_superblock() -> Result<()> {
+    use std::os::unix::fs::FileExt;
+
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("space_map");
+
+    // create() is the first commit, with its superblock in block 1
+    let mut sm = DiskSpaceMap::create(&path, 1000)?;
+    for b in 0..100 {
+        sm.mark_allocated(b)?;
+    }
+    sm.flush()?;
+    for b in 100..200 {
+        sm.mark_allocated(b)?;
+    }
+    sm.flush()?;
+    drop(sm);
+
+    // tear the last commit's superblock, the one before still describes
+    // its own copies of the index and bitmap
+    let file = std::fs::OpenOptions::new().write(true).open(&path)?;
+    file.write_all_at(&[0xa5; 64], BLOCK_SIZE as u64)?;
+
+    let mut sm = DiskSpaceMap::open(&path)?;
+    ensure!(sm.nr_free() == 900);
+    ensure!(sm.find_free(0, 1000) == Some(100));
+
+    // and carries on committing from there
+    sm.mark_allocated(100)?;
+    sm.flush()?;
+    drop(sm);
+    let sm = DiskSpaceMap::open(&path)?;
+    ensure!(sm.nr_free() == 899);
+
+    Ok(())
+}
+
+#[test]
+fn mmap_space_map_survives_torn_header() -> Result<()> {
+    use std::os::unix::fs::FileExt;
+
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("space_map");
+
+    let mut sm = MmapSpaceMap::create(&path, 1000)?;
+    for b in 0..100 {
+        sm.mark_allocated(b)?;
+    }
+    sm.sync()?;
+    sm.resize(2000)?;
+    drop(sm);
+
+    // tear the most recent header, the older one still describes 1000
+    // blocks
+    let file = std::fs::OpenOptions::new().write(true).open(&path)?;
+    file.write_all_at(&[0xff; 8], 64)?;
+
+    let sm = MmapSpaceMap::open(&path)?;
+    ensure!(sm.nr_blocks() == 1000);
+    ensure!(sm.nr_free() == 900);
+    ensure!(sm.find_free(0, 1000) == Some(100));
+
+    Ok(())
+}
+
+#[test]
+fn mmap_space_map_shrinks() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("space_map");
+
+    let mut sm = MmapSpaceMap::create(&path, 1000)?;
+    sm.mark_allocated(10)?;
+    sm.mark_allocated(990)?;
+    sm.resize(500)?;
+    ensure!(sm.nr_free() == 499);
+    sm.resize(1000)?;
+    ensure!(sm.is_free(990));
+    ensure!(sm.nr_free() == 999);
+    drop(sm);
+
+    let sm = MmapSpaceMap::open(&path)?;
+    ensure!(sm.nr_blocks() == 1000);
+    ensure!(sm.nr_free() == 999);
+
+    Ok(())
+}
+
+//----------------------------------------------------------------
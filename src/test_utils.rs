@@ -0,0 +1,37 @@
+// Helpers shared by the unit tests.
+
+use roaring::RoaringBitmap;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use crate::discard::DiscardSink;
+
+//----------------------------------------------------------------
+
+// Allocates the first block within begin..end that isn't in allocated.
+pub fn alloc_block(allocated: &mut RoaringBitmap, begin: u64, end: u64) -> io::Result<Option<u64>> {
+    for block in begin..end {
+        if !allocated.contains(block as u32) {
+            allocated.insert(block as u32);
+            return Ok(Some(block));
+        }
+    }
+    Ok(None)
+}
+
+//----------------------------------------------------------------
+
+// A discard sink that records what it's asked to discard.
+#[derive(Clone, Default)]
+pub struct Recorder {
+    pub discards: Arc<Mutex<Vec<(u64, u64)>>>,
+}
+
+impl DiscardSink for Recorder {
+    fn discard(&mut self, begin: u64, end: u64) -> io::Result<()> {
+        self.discards.lock().unwrap().push((begin, end));
+        Ok(())
+    }
+}
+
+//----------------------------------------------------------------
@@ -0,0 +1,118 @@
+use std::io;
+
+use crate::allocator::*;
+use crate::tree::Priority;
+
+#[cfg(test)]
+mod tests;
+
+//----------------------------------------------------------------
+
+pub type TierId = usize;
+
+// Tiers are laid out back to back, fastest first.
+#[derive(Clone, Copy, Debug)]
+pub struct TierConfig {
+    pub nr_blocks: u64,
+    pub nr_nodes: u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TierUsage {
+    pub nr_blocks: u64,
+    pub nr_free: u64,
+}
+
+// An allocator over several tiers of storage, eg. an SSD backed front
+// range and an HDD backed tail.  Each tier is an allocation class;
+// contexts prefer one tier, and spill over into the next when it's
+// full (the slowest wraps around to the fastest).
+pub struct TieredAllocator {
+    allocator: Allocator,
+    tiers: Vec<(u64, u64)>,
+}
+
+impl TieredAllocator {
+    pub fn new(configs: &[TierConfig]) -> Self {
+        assert!(!configs.is_empty());
+
+        let mut tiers = Vec::new();
+        let mut classes = Vec::new();
+        let mut begin = 0;
+        for (i, config) in configs.iter().enumerate() {
+            let end = begin + config.nr_blocks;
+            tiers.push((begin, end));
+            classes.push(ClassConfig {
+                spill_to: if configs.len() > 1 {
+                    Some((i + 1) % configs.len())
+                } else {
+                    None
+                },
+                ..ClassConfig::new(begin, end, config.nr_nodes)
+            });
+            begin = end;
+        }
+
+        Self {
+            allocator: Allocator::with_classes(&classes),
+            tiers,
+        }
+    }
+
+    pub fn nr_tiers(&self) -> usize {
+        self.tiers.len()
+    }
+
+    // The tier a block lives in.
+    pub fn tier_of(&self, block: u64) -> Option<TierId> {
+        self.tiers
+            .iter()
+            .position(|(begin, end)| (*begin..*end).contains(&block))
+    }
+
+    #[track_caller]
+    pub fn get_context(&mut self, preferred: TierId) -> ContextGuard {
+        self.allocator.get_context_in(preferred, Priority::Normal)
+    }
+
+    pub fn put_context(&mut self, context: ContextGuard) {
+        self.allocator.put_context(context);
+    }
+
+    pub fn alloc<F>(&mut self, context: &ContextGuard, f: F) -> io::Result<Option<u64>>
+    where
+        F: FnMut(u64, u64) -> io::Result<Option<u64>>,
+    {
+        self.allocator.alloc(context, f)
+    }
+
+    pub fn alloc_many<F>(
+        &mut self,
+        context: &ContextGuard,
+        n: u64,
+        out: &mut Vec<u64>,
+        f: F,
+    ) -> io::Result<u64>
+    where
+        F: FnMut(u64, u64) -> io::Result<Option<u64>>,
+    {
+        self.allocator.alloc_many(context, n, out, f)
+    }
+
+    pub fn usage(&self) -> Vec<TierUsage> {
+        self.tiers
+            .iter()
+            .enumerate()
+            .map(|(tier, (begin, end))| TierUsage {
+                nr_blocks: end - begin,
+                nr_free: self.allocator.nr_free_blocks_in(tier),
+            })
+            .collect()
+    }
+
+    pub fn reset(&mut self) {
+        self.allocator.reset();
+    }
+}
+
+//----------------------------------------------------------------
@@ -0,0 +1,91 @@
+use anyhow::{ensure, Result};
+use roaring::RoaringBitmap;
+
+use crate::test_utils::*;
+use crate::tiered::*;
+
+//----------------------------------------------------------------
+
+fn hybrid() -> TieredAllocator {
+    TieredAllocator::new(&[
+        TierConfig {
+            nr_blocks: 128,
+            nr_nodes: 7,
+        },
+        TierConfig {
+            nr_blocks: 896,
+            nr_nodes: 31,
+        },
+    ])
+}
+
+//----------------------------------------------------------------
+
+#[test]
+fn tier_preference() -> Result<()> {
+    let mut allocator = hybrid();
+    let mut allocated = RoaringBitmap::new();
+
+    let fast = allocator.get_context(0);
+    let slow = allocator.get_context(1);
+
+    let mut blocks = Vec::new();
+    allocator.alloc_many(&fast, 64, &mut blocks, |b, e| {
+        alloc_block(&mut allocated, b, e)
+    })?;
+    ensure!(blocks.iter().all(|b| allocator.tier_of(*b) == Some(0)));
+
+    blocks.clear();
+    allocator.alloc_many(&slow, 64, &mut blocks, |b, e| {
+        alloc_block(&mut allocated, b, e)
+    })?;
+    ensure!(blocks.iter().all(|b| allocator.tier_of(*b) == Some(1)));
+
+    let usage = allocator.usage();
+    ensure!(
+        usage
+            == vec![
+                TierUsage {
+                    nr_blocks: 128,
+                    nr_free: 64
+                },
+                TierUsage {
+                    nr_blocks: 896,
+                    nr_free: 832
+                },
+            ]
+    );
+
+    allocator.put_context(fast);
+    allocator.put_context(slow);
+
+    Ok(())
+}
+
+#[test]
+fn tier_spills_over_when_full() -> Result<()> {
+    let mut allocator = hybrid();
+    let mut allocated = RoaringBitmap::new();
+
+    // Fill the slow tier, then keep going.
+    let slow = allocator.get_context(1);
+    let mut blocks = Vec::new();
+    let n = allocator.alloc_many(&slow, 1000, &mut blocks, |b, e| {
+        alloc_block(&mut allocated, b, e)
+    })?;
+    ensure!(n == 1000);
+    ensure!(
+        blocks
+            .iter()
+            .filter(|b| allocator.tier_of(**b) == Some(0))
+            .count()
+            == 104
+    );
+    ensure!(allocator.usage()[1].nr_free == 0);
+
+    allocator.put_context(slow);
+
+    Ok(())
+}
+
+//----------------------------------------------------------------
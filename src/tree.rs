@@ -1,5 +1,11 @@
+use std::collections::BTreeMap;
+use std::io;
 use std::sync::{Arc, Mutex};
 
+use crate::journal::{Event, TreeImage};
+use crate::range_set::RangeSet;
+use crate::space_map::SpaceMap;
+
 pub mod utils;
 
 #[cfg(test)]
@@ -11,11 +17,27 @@ pub const NULL_NODE: u8 = 255;
 
 //----------------------------------------------------------------
 
+// Privileged borrowers may dip into the emergency reserve.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Priority {
+    #[default]
+    Normal,
+    Privileged,
+}
+
+//----------------------------------------------------------------
+
 #[derive(Clone, Copy, Debug)]
 pub struct Extent {
     pub begin: u64,
     pub end: u64,
     pub cursor: u64,
+
+    // Aligned allocations have searched up to here.
+    pub aligned: u64,
+
+    // Free blocks in cursor..end
+    pub nr_free: u64,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -52,10 +74,7 @@ impl Node {
     pub fn nr_free_blocks(&self) -> u64 {
         match self {
             Node::Internal(node) => node.nr_free_blocks,
-            Node::Leaf(node) => {
-                let extent = node.extent.lock().unwrap();
-                extent.end - extent.cursor
-            }
+            Node::Leaf(node) => node.extent.lock().unwrap().nr_free,
         }
     }
 }
@@ -74,26 +93,91 @@ impl Default for Node {
 
 //----------------------------------------------------------------
 
+// An immutable copy of a tree's shape, cursors and holder counts.  The
+// extents are copies, so later allocations don't show through.
+#[derive(Clone, Debug)]
+pub struct TreeCheckpoint {
+    nr_blocks: u64,
+    nodes: Vec<Node>,
+    free_nodes: Vec<u8>,
+    root: u8,
+    blocked: RangeSet,
+}
+
+impl TreeCheckpoint {
+    pub fn nr_holders(&self) -> usize {
+        if self.root == NULL_NODE {
+            0
+        } else {
+            self.nodes[self.root as usize].nr_holders()
+        }
+    }
+}
+
+fn copy_node(node: &Node, extent: impl FnOnce(&Extent) -> Arc<Mutex<Extent>>) -> Node {
+    match node {
+        Node::Leaf(leaf) => Node::Leaf(Leaf {
+            extent: extent(&leaf.extent.lock().unwrap()),
+            holders: leaf.holders,
+        }),
+        node => node.clone(),
+    }
+}
+
+//----------------------------------------------------------------
+
 pub struct Tree {
+    // The tree covers the blocks begin..nr_blocks
+    begin: u64,
     nr_blocks: u64,
     nodes: Vec<Node>,
     free_nodes: Vec<u8>,
     root: u8,
+
+    // Ranges that no new borrow may land in.
+    blocked: RangeSet,
+
+    // Bad blocks, which are never handed out.  Unlike blocked ranges
+    // these survive resets and rollbacks.
+    bad: RangeSet,
+
+    // Fraction of the blocks that only privileged borrowers may use.
+    emergency_fraction: f64,
+
+    // Extents whose free counts were estimated when a leaf was split,
+    // and should be recounted from the space map.
+    stale: Vec<Arc<Mutex<Extent>>>,
+
+    // Events not yet taken by the journal, if journalling.
+    events: Option<Vec<Event>>,
 }
 
 impl Tree {
     pub fn new(nr_blocks: u64, nr_nodes: u8) -> Self {
+        Self::new_region(0, nr_blocks, nr_nodes)
+    }
+
+    // A tree that only hands out blocks from begin..end
+    pub fn new_region(begin: u64, end: u64, nr_nodes: u8) -> Self {
         #[allow(clippy::absurd_extreme_comparisons)]
         {
             assert!(nr_nodes <= NULL_NODE);
         }
 
         let free_nodes = (0u8..nr_nodes).collect::<Vec<u8>>();
+        assert!(begin <= end);
+
         let mut tree = Tree {
-            nr_blocks,
+            begin,
+            nr_blocks: end,
             nodes: vec![Node::default(); nr_nodes as usize],
             free_nodes,
             root: NULL_NODE,
+            blocked: RangeSet::new(),
+            bad: RangeSet::new(),
+            emergency_fraction: 0.0,
+            stale: Vec::new(),
+            events: None,
         };
 
         tree.setup_initial_root();
@@ -105,14 +189,32 @@ impl Tree {
         self.root = self.alloc_node().unwrap();
         self.nodes[self.root as usize] = Node::Leaf(Leaf {
             extent: Arc::new(Mutex::new(Extent {
-                begin: 0,
+                begin: self.begin,
                 end: self.nr_blocks,
-                cursor: 0,
+                cursor: self.begin,
+                aligned: self.begin,
+                nr_free: self.nr_blocks - self.begin - self.nr_bad_in(self.begin, self.nr_blocks),
             })),
             holders: 0,
         });
     }
 
+    pub fn region(&self) -> (u64, u64) {
+        (self.begin, self.nr_blocks)
+    }
+
+    pub fn len(&self) -> u64 {
+        self.nr_blocks - self.begin
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains(&self, block: u64) -> bool {
+        (self.begin..self.nr_blocks).contains(&block)
+    }
+
     fn alloc_node(&mut self) -> Option<u8> {
         self.free_nodes.pop()
     }
@@ -153,12 +255,32 @@ impl Tree {
 
                 let copy = *extent;
                 let mid = extent.cursor + (extent.end - extent.cursor) / 2;
+
+                // Share the free blocks out in proportion until they can
+                // be recounted.
+                let right_free = copy.nr_free * (copy.end - mid) / (copy.end - copy.cursor);
                 extent.end = mid;
+                extent.aligned = extent.aligned.min(mid);
+                extent.nr_free = copy.nr_free - right_free;
                 drop(extent);
 
                 let left_child = self.alloc_node().unwrap();
                 let right_child = self.alloc_node().unwrap();
 
+                let right_extent = Arc::new(Mutex::new(Extent {
+                    begin: mid,
+                    end: copy.end,
+                    cursor: mid,
+                    aligned: mid,
+                    nr_free: right_free,
+                }));
+                self.mark_stale(&leaf.extent);
+                self.mark_stale(&right_extent);
+                self.record(Event::Split {
+                    begin: copy.begin,
+                    cut: mid,
+                });
+
                 self.write_node(
                     left_child,
                     Node::Leaf(Leaf {
@@ -169,11 +291,7 @@ impl Tree {
                 self.write_node(
                     right_child,
                     Node::Leaf(Leaf {
-                        extent: Arc::new(Mutex::new(Extent {
-                            begin: mid,
-                            end: copy.end,
-                            cursor: mid,
-                        })),
+                        extent: right_extent,
                         holders: 0,
                     }),
                 );
@@ -185,7 +303,7 @@ impl Tree {
                     Node::Internal(Internal {
                         cut: mid,
                         holders: nr_holders,
-                        nr_free_blocks: copy.end - copy.cursor,
+                        nr_free_blocks: copy.nr_free,
                         left: left_child,
                         right: right_child,
                     }),
@@ -218,7 +336,7 @@ impl Tree {
         }
     }
 
-    fn borrow_(&mut self, node_index: u8) -> Option<Arc<Mutex<Extent>>> {
+    fn borrow_(&mut self, node_index: u8, exclude: &[u64]) -> Option<Arc<Mutex<Extent>>> {
         if node_index == NULL_NODE {
             return None;
         }
@@ -228,9 +346,15 @@ impl Tree {
             Node::Internal(node) => {
                 let extent = match (node.left, node.right) {
                     (255, 255) => panic!("node with two NULLs shouldn't be possible"),
-                    (255, right) => self.borrow_(right),
-                    (left, 255) => self.borrow_(left),
-                    (left, right) => self.borrow_(self.select_child(left, right)),
+                    (255, right) => self.borrow_(right, exclude),
+                    (left, 255) => self.borrow_(left, exclude),
+                    (left, right) => {
+                        // Fall back to the other child if the preferred one is blocked.
+                        let first = self.select_child(left, right);
+                        let second = if first == left { right } else { left };
+                        self.borrow_(first, exclude)
+                            .or_else(|| self.borrow_(second, exclude))
+                    }
                 };
 
                 if extent.is_some() {
@@ -249,11 +373,17 @@ impl Tree {
             }
 
             Node::Leaf(node) => {
+                if self.is_leaf_blocked(&node)
+                    || exclude.contains(&node.extent.lock().unwrap().begin)
+                {
+                    return None;
+                }
+
                 if node.holders > 0 {
                     // Someone is already using this extent.  See if we can split it.
                     if self.split_leaf(node_index) {
                         // Try again, now that this node is an internal node
-                        self.borrow_(node_index)
+                        self.borrow_(node_index, exclude)
                     } else {
                         // We can't split the leaf, so we'll have to share.
                         self.write_node(
@@ -284,7 +414,222 @@ impl Tree {
     // cause existing regions to be altered as new splits are
     // introduced to the BSP tree.
     pub fn borrow(&mut self) -> Option<Arc<Mutex<Extent>>> {
-        self.borrow_(self.root)
+        self.borrow_with(Priority::Normal, &[])
+    }
+
+    // As borrow(), but never returns an extent beginning at one of the
+    // blocks in exclude.
+    pub fn borrow_excluding(&mut self, exclude: &[u64]) -> Option<Arc<Mutex<Extent>>> {
+        self.borrow_with(Priority::Normal, exclude)
+    }
+
+    pub fn borrow_with(
+        &mut self,
+        priority: Priority,
+        exclude: &[u64],
+    ) -> Option<Arc<Mutex<Extent>>> {
+        if priority == Priority::Normal && self.nr_free_blocks() <= self.nr_emergency_blocks() {
+            return None;
+        }
+
+        let extent = self.borrow_(self.root, exclude)?;
+        let begin = extent.lock().unwrap().begin;
+        self.record(Event::Borrow { begin });
+        Some(extent)
+    }
+
+    // Sets aside a fraction of the blocks that only privileged borrowers
+    // may use.
+    pub fn set_emergency_reserve(&mut self, fraction: f64) {
+        assert!((0.0..=1.0).contains(&fraction));
+        self.emergency_fraction = fraction;
+    }
+
+    pub fn nr_emergency_blocks(&self) -> u64 {
+        (self.len() as f64 * self.emergency_fraction) as u64
+    }
+
+    // The number of free blocks across all the leaves.  Unlike the
+    // counts cached in internal nodes this is always up to date.
+    pub fn nr_free_blocks(&self) -> u64 {
+        let mut total = 0;
+        let mut stack = vec![self.root];
+        while let Some(node_index) = stack.pop() {
+            if node_index == NULL_NODE {
+                continue;
+            }
+
+            match &self.nodes[node_index as usize] {
+                Node::Internal(node) => {
+                    stack.push(node.left);
+                    stack.push(node.right);
+                }
+                leaf => total += leaf.nr_free_blocks(),
+            }
+        }
+        total
+    }
+
+    // The parts of bad ranges within begin..end.
+    fn bad_in(&self, begin: u64, end: u64) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.bad
+            .iter()
+            .map(move |(b, e)| (b.max(begin), e.min(end)))
+            .filter(|(b, e)| b < e)
+    }
+
+    fn nr_bad_in(&self, begin: u64, end: u64) -> u64 {
+        self.bad_in(begin, end).map(|(b, e)| e - b).sum()
+    }
+
+    // Free blocks in begin..end according to the space map, less any that
+    // are bad, since they'll never be handed out.
+    fn count_free<S: SpaceMap + ?Sized>(&self, sm: &S, begin: u64, end: u64) -> u64 {
+        let nr_bad_free: u64 = self
+            .bad_in(begin, end)
+            .map(|(b, e)| sm.count_free(b, e))
+            .sum();
+        sm.count_free(begin, end) - nr_bad_free
+    }
+
+    // Free blocks in the space map that lie in bad or blocked ranges, so
+    // the tree will never hand them out.
+    pub fn nr_unusable_free<S: SpaceMap + ?Sized>(&self, sm: &S) -> u64 {
+        let mut unusable = self.bad.clone();
+        for (begin, end) in self.blocked.iter() {
+            unusable.insert(begin, end);
+        }
+
+        unusable
+            .iter()
+            .map(|(begin, end)| (begin.max(self.begin), end.min(self.nr_blocks)))
+            .filter(|(begin, end)| begin < end)
+            .map(|(begin, end)| sm.count_free(begin, end))
+            .sum()
+    }
+
+    // Internal nodes on the path from the root to block b's leaf.
+    fn path_to(&self, b: u64) -> (Vec<u8>, u8) {
+        let mut path = Vec::new();
+        let mut node_index = self.root;
+        while node_index != NULL_NODE {
+            match &self.nodes[node_index as usize] {
+                Node::Internal(node) => {
+                    path.push(node_index);
+                    node_index = if b < node.cut { node.left } else { node.right };
+                }
+                Node::Leaf(_) => break,
+            }
+        }
+        (path, node_index)
+    }
+
+    fn adjust_free(&mut self, path: &[u8], delta: i64) {
+        for node_index in path {
+            if let Node::Internal(node) = self.get_mut(*node_index) {
+                node.nr_free_blocks = node.nr_free_blocks.saturating_add_signed(delta);
+            }
+        }
+    }
+
+    // Records that len blocks starting at b have been allocated.  The
+    // caller has the extent locked, and has already adjusted its nr_free.
+    pub fn allocated(&mut self, b: u64, len: u64) {
+        let (path, _) = self.path_to(b);
+        self.adjust_free(&path, -(len as i64));
+    }
+
+    // Records that block b has been freed.  Returns false if it isn't
+    // ahead of a cursor, in which case it won't be seen until a rescan.
+    pub fn freed(&mut self, b: u64) -> bool {
+        let (path, leaf_index) = self.path_to(b);
+        if leaf_index == NULL_NODE {
+            return false;
+        }
+
+        let Node::Leaf(leaf) = &self.nodes[leaf_index as usize] else {
+            return false;
+        };
+
+        let mut extent = leaf.extent.lock().unwrap();
+        if b < extent.cursor || b >= extent.end {
+            return false;
+        }
+        extent.nr_free += 1;
+        drop(extent);
+
+        self.adjust_free(&path, 1);
+        self.record(Event::Free { b });
+        true
+    }
+
+    // Moves the cursor of block b's extent back to b, if it's just past
+    // the len blocks at b.  Returns false if something has been allocated
+    // from the extent since.
+    pub fn rewind(&mut self, b: u64, len: u64) -> bool {
+        let (_, leaf_index) = self.path_to(b);
+        if leaf_index == NULL_NODE {
+            return false;
+        }
+
+        let Node::Leaf(leaf) = &self.nodes[leaf_index as usize] else {
+            return false;
+        };
+
+        let mut extent = leaf.extent.lock().unwrap();
+        if b < extent.begin || extent.cursor != b + len {
+            return false;
+        }
+        extent.cursor = b;
+        if extent.aligned == b + len {
+            extent.aligned = b;
+        }
+        let begin = extent.begin;
+        drop(extent);
+
+        self.advanced(begin, b);
+        true
+    }
+
+    // Queues an extent for recount().  Each is queued once, and those only
+    // the queue still refers to belong to leaves that have gone, so they're
+    // dropped.  That keeps the queue no bigger than the tree when there's
+    // no space map to recount from.
+    fn mark_stale(&mut self, extent: &Arc<Mutex<Extent>>) {
+        self.stale.retain(|e| Arc::strong_count(e) > 1);
+        if !self.stale.iter().any(|e| Arc::ptr_eq(e, extent)) {
+            self.stale.push(extent.clone());
+        }
+    }
+
+    // Replaces any estimated free counts with real ones from the space map.
+    pub fn recount<S: SpaceMap + ?Sized>(&mut self, sm: &S) {
+        if self.stale.is_empty() {
+            return;
+        }
+
+        for extent in std::mem::take(&mut self.stale) {
+            let mut extent = extent.lock().unwrap();
+            extent.nr_free = self.count_free(sm, extent.cursor, extent.end);
+        }
+        self.recount_(self.root);
+    }
+
+    fn recount_(&mut self, node_index: u8) -> u64 {
+        if node_index == NULL_NODE {
+            return 0;
+        }
+
+        match self.read_node(node_index) {
+            Node::Internal(node) => {
+                let nr_free = self.recount_(node.left) + self.recount_(node.right);
+                if let Node::Internal(node) = self.get_mut(node_index) {
+                    node.nr_free_blocks = nr_free;
+                }
+                nr_free
+            }
+            leaf => leaf.nr_free_blocks(),
+        }
     }
 
     fn nr_free(&self, node_index: u8) -> u64 {
@@ -296,9 +641,176 @@ impl Tree {
         node.nr_free_blocks()
     }
 
+    fn is_leaf_blocked(&self, leaf: &Leaf) -> bool {
+        let extent = leaf.extent.lock().unwrap();
+        self.unblocked_run(extent.cursor, extent.end).is_none()
+    }
+
+    // Stops any new borrows landing in begin..end.  Extents that are
+    // already held are unaffected, use unblocked_run() to avoid the
+    // blocked parts of them.
+    pub fn block_range(&mut self, begin: u64, end: u64) {
+        self.blocked.insert(begin, end);
+    }
+
+    // Cursors may have been moved past the range while it was blocked, so
+    // they're wound back to it.  The free counts are estimates until the
+    // next recount().
+    pub fn unblock_range(&mut self, begin: u64, end: u64) {
+        self.blocked.remove(begin, end);
+
+        let extents: Vec<_> = self
+            .nodes_in_use()
+            .filter_map(|(_, node)| match node {
+                Node::Leaf(leaf) => Some(leaf.extent.clone()),
+                Node::Internal(_) => None,
+            })
+            .collect();
+
+        let mut rewound = false;
+        for extent_ref in extents {
+            let mut extent = extent_ref.lock().unwrap();
+            let b = begin.max(extent.begin);
+            let e = end.min(extent.cursor);
+            if b >= e {
+                continue;
+            }
+
+            extent.nr_free += e - b - self.nr_bad_in(b, e);
+            extent.cursor = b;
+            extent.aligned = extent.aligned.min(b);
+            let extent_begin = extent.begin;
+            drop(extent);
+
+            self.advanced(extent_begin, b);
+            self.mark_stale(&extent_ref);
+            rewound = true;
+        }
+
+        if rewound {
+            self.recount_(self.root);
+        }
+    }
+
+    // Returns the first run within begin..end that isn't blocked or bad.
+    pub fn unblocked_run(&self, begin: u64, end: u64) -> Option<(u64, u64)> {
+        let mut pos = begin;
+        while let Some((b, e)) = self.blocked.first_gap(pos, end) {
+            if let Some(run) = self.bad.first_gap(b, e) {
+                return Some(run);
+            }
+            pos = e;
+        }
+        None
+    }
+
+    pub fn bad_blocks(&self) -> &RangeSet {
+        &self.bad
+    }
+
+    // Retires begin..end.  Leaves are cut back, or split, so their
+    // cursor..end no longer covers it.  If there aren't the nodes to
+    // split a leaf, unblocked_run() still steers allocation around it.
+    pub fn mark_bad(&mut self, begin: u64, end: u64) {
+        let (begin, end) = (begin.max(self.begin), end.min(self.nr_blocks));
+        if begin >= end {
+            return;
+        }
+        self.bad.insert(begin, end);
+
+        let leaves: Vec<u8> = self
+            .nodes_in_use()
+            .filter(|(_, node)| matches!(node, Node::Leaf(_)))
+            .map(|(node_index, _)| node_index)
+            .collect();
+        for leaf_index in leaves {
+            self.carve_leaf(leaf_index, begin, end);
+        }
+        self.recount_(self.root);
+
+        self.record(Event::Bad { begin, end });
+        self.record_snapshot();
+    }
+
+    fn carve_leaf(&mut self, leaf_index: u8, begin: u64, end: u64) {
+        let Node::Leaf(leaf) = self.read_node(leaf_index) else {
+            return;
+        };
+
+        let mut extent = leaf.extent.lock().unwrap();
+        if end <= extent.cursor || begin >= extent.end {
+            return;
+        }
+        let nr_bad = end.min(extent.end) - begin.max(extent.cursor);
+        extent.nr_free = extent.nr_free.saturating_sub(nr_bad);
+        self.mark_stale(&leaf.extent);
+
+        if begin <= extent.cursor {
+            extent.cursor = end.min(extent.end);
+            extent.nr_free = extent.nr_free.min(extent.end - extent.cursor);
+            let (b, cursor) = (extent.begin, extent.cursor);
+            drop(extent);
+            self.advanced(b, cursor);
+        } else if end >= extent.end {
+            extent.end = begin;
+            extent.aligned = extent.aligned.min(begin);
+            extent.nr_free = extent.nr_free.min(begin - extent.cursor);
+        } else if self.free_nodes.len() >= 2 {
+            let copy = *extent;
+            let right_free = copy.nr_free * (copy.end - end) / (copy.end - copy.cursor - nr_bad);
+            extent.end = begin;
+            extent.aligned = extent.aligned.min(begin);
+            extent.nr_free = (copy.nr_free - right_free).min(begin - copy.cursor);
+            drop(extent);
+
+            let left_child = self.alloc_node().unwrap();
+            let right_child = self.alloc_node().unwrap();
+            let right_extent = Arc::new(Mutex::new(Extent {
+                begin: end,
+                end: copy.end,
+                cursor: end,
+                aligned: end,
+                nr_free: right_free,
+            }));
+            self.mark_stale(&right_extent);
+
+            self.write_node(
+                left_child,
+                Node::Leaf(Leaf {
+                    extent: leaf.extent,
+                    holders: leaf.holders,
+                }),
+            );
+            self.write_node(
+                right_child,
+                Node::Leaf(Leaf {
+                    extent: right_extent,
+                    holders: 0,
+                }),
+            );
+            self.write_node(
+                leaf_index,
+                Node::Internal(Internal {
+                    cut: end,
+                    holders: leaf.holders,
+                    nr_free_blocks: copy.nr_free,
+                    left: left_child,
+                    right: right_child,
+                }),
+            );
+        }
+    }
+
     // Returns the node_index of the replacement for this node (commonly the same as node_index)
     #[allow(clippy::only_used_in_recursion)]
-    fn release_(&mut self, block: u64, begin: u64, end: u64, node_index: u8) -> (u8, usize) {
+    fn release_(
+        &mut self,
+        block: u64,
+        begin: u64,
+        end: u64,
+        node_index: u8,
+        nr_holders: usize,
+    ) -> (u8, usize) {
         if node_index == NULL_NODE {
             return (node_index, 0);
         }
@@ -314,9 +826,9 @@ impl Tree {
 
                 // FIXME: refactor
                 if block < node.cut {
-                    (left, delta) = self.release_(block, begin, node.cut, node.left);
+                    (left, delta) = self.release_(block, begin, node.cut, node.left, nr_holders);
                 } else {
-                    (right, delta) = self.release_(block, node.cut, end, node.right);
+                    (right, delta) = self.release_(block, node.cut, end, node.right, nr_holders);
                 }
 
                 if left == NULL_NODE && right == NULL_NODE {
@@ -346,7 +858,7 @@ impl Tree {
             }
 
             Node::Leaf(node) => {
-                assert!(node.holders > 0);
+                assert!(node.holders >= nr_holders);
 
                 // See if the extent is now empty
                 let extent = node.extent.lock().unwrap();
@@ -365,16 +877,21 @@ impl Tree {
                         node_index,
                         Node::Leaf(Leaf {
                             extent: node.extent,
-                            holders: node.holders - 1,
+                            holders: node.holders - nr_holders,
                         }),
                     );
-                    (node_index, 1)
+                    (node_index, nr_holders)
                 }
             }
         }
     }
 
     pub fn release(&mut self, extent: Arc<Mutex<Extent>>) {
+        self.release_holders(extent, 1);
+    }
+
+    // Drops nr_holders holds on the extent at once.
+    pub fn release_holders(&mut self, extent: Arc<Mutex<Extent>>, nr_holders: usize) {
         // eprintln!("before release:");
         // utils::dump_tree(&self);
 
@@ -382,7 +899,8 @@ impl Tree {
         let b = extent.begin;
         drop(extent);
 
-        (self.root, _) = self.release_(b, 0, self.nr_blocks, self.root);
+        (self.root, _) = self.release_(b, self.begin, self.nr_blocks, self.root, nr_holders);
+        self.record(Event::Release { begin: b });
 
         // eprintln!("after release:");
         // utils::dump_tree(&self);
@@ -409,17 +927,355 @@ impl Tree {
 
     fn reset_(&mut self, nr_blocks: u64) {
         self.free_tree(self.root);
+        self.stale.clear();
         self.nr_blocks = nr_blocks;
         self.setup_initial_root();
+        self.record_snapshot();
     }
 
     pub fn reset(&mut self) {
         self.reset_(self.nr_blocks);
     }
 
+    // Rebuilds the tree from the space map, so blocks that cursors have
+    // skipped over, but which are now free, can be borrowed again.  The
+    // tree is pre-split, using up to half the nodes, so fully allocated
+    // regions are pruned and the free counts reflect the space map.
+    pub fn rebuild<S: SpaceMap + ?Sized>(&mut self, sm: &S) {
+        self.free_tree(self.root);
+        self.stale.clear();
+
+        let nr_nodes = self.free_nodes.len() as u64;
+        let depth = (nr_nodes + 1).ilog2().saturating_sub(2);
+        self.root = self.seed_(sm, self.begin, self.nr_blocks, depth);
+        self.record_snapshot();
+    }
+
+    fn seed_<S: SpaceMap + ?Sized>(&mut self, sm: &S, begin: u64, end: u64, depth: u32) -> u8 {
+        let Some(cursor) = sm.find_free(begin, end) else {
+            // Fully allocated, so there's no need for a node.
+            return NULL_NODE;
+        };
+
+        let node_index = self.alloc_node().unwrap();
+
+        // Split at the same place split_leaf() would.
+        let mid = cursor + (end - cursor) / 2;
+        if depth > 0 && end - cursor > 16 && self.free_nodes.len() >= 2 {
+            let left = self.seed_(sm, begin, mid, depth - 1);
+            let right = self.seed_(sm, mid, end, depth - 1);
+
+            if left == NULL_NODE || right == NULL_NODE {
+                self.free_node(node_index);
+                return if left == NULL_NODE { right } else { left };
+            }
+
+            self.write_node(
+                node_index,
+                Node::Internal(Internal {
+                    holders: 0,
+                    nr_free_blocks: self.count_free(sm, cursor, end),
+                    cut: mid,
+                    left,
+                    right,
+                }),
+            );
+        } else {
+            self.write_node(
+                node_index,
+                Node::Leaf(Leaf {
+                    extent: Arc::new(Mutex::new(Extent {
+                        begin,
+                        end,
+                        cursor,
+                        aligned: cursor,
+                        nr_free: self.count_free(sm, cursor, end),
+                    })),
+                    holders: 0,
+                }),
+            );
+        }
+
+        node_index
+    }
+
+    // Moves the end of the tree's region to nr_blocks
     pub fn resize(&mut self, nr_blocks: u64) {
+        assert!(nr_blocks >= self.begin);
         self.reset_(nr_blocks);
     }
+
+    pub fn checkpoint(&self) -> TreeCheckpoint {
+        // Only copy the nodes in use, free ones may hold stale extents.
+        let mut nodes = vec![Node::default(); self.nodes.len()];
+        for (node_index, node) in self.nodes_in_use() {
+            nodes[node_index as usize] = copy_node(node, |e| Arc::new(Mutex::new(*e)));
+        }
+
+        TreeCheckpoint {
+            nr_blocks: self.nr_blocks,
+            nodes,
+            free_nodes: self.free_nodes.clone(),
+            root: self.root,
+            blocked: self.blocked.clone(),
+        }
+    }
+
+    // Restores a checkpoint.  Extents that begin at the same block as one
+    // that's currently live keep their identity, so contexts holding them
+    // can carry on; use hold() to count them again.  All holder counts
+    // start at zero.  Blocks allocated or freed since the checkpoint
+    // aren't reflected in its free counts, so every extent is left for
+    // recount() to correct.
+    pub fn rollback(&mut self, checkpoint: &TreeCheckpoint) {
+        let live: BTreeMap<u64, Arc<Mutex<Extent>>> = self
+            .nodes_in_use()
+            .filter_map(|(_, node)| match node {
+                Node::Leaf(leaf) => Some((leaf.extent.lock().unwrap().begin, leaf.extent.clone())),
+                _ => None,
+            })
+            .collect();
+
+        self.nodes = checkpoint
+            .nodes
+            .iter()
+            .map(|node| {
+                copy_node(node, |e| match live.get(&e.begin) {
+                    Some(extent) => {
+                        *extent.lock().unwrap() = *e;
+                        extent.clone()
+                    }
+                    None => Arc::new(Mutex::new(*e)),
+                })
+            })
+            .collect();
+        for node in &mut self.nodes {
+            match node {
+                Node::Internal(node) => node.holders = 0,
+                Node::Leaf(leaf) => leaf.holders = 0,
+            }
+        }
+
+        self.nr_blocks = checkpoint.nr_blocks;
+        self.free_nodes = checkpoint.free_nodes.clone();
+        self.root = checkpoint.root;
+        self.blocked = checkpoint.blocked.clone();
+
+        self.stale.clear();
+        let extents: Vec<_> = self
+            .nodes_in_use()
+            .filter_map(|(_, node)| match node {
+                Node::Leaf(leaf) => Some(leaf.extent.clone()),
+                Node::Internal(_) => None,
+            })
+            .collect();
+        for extent in &extents {
+            self.mark_stale(extent);
+        }
+        self.record_snapshot();
+    }
+
+    fn nodes_in_use(&self) -> impl Iterator<Item = (u8, &Node)> + '_ {
+        let mut stack = vec![self.root];
+        std::iter::from_fn(move || loop {
+            let node_index = stack.pop()?;
+            if node_index == NULL_NODE {
+                continue;
+            }
+
+            let node = &self.nodes[node_index as usize];
+            if let Node::Internal(node) = node {
+                stack.push(node.right);
+                stack.push(node.left);
+            }
+            return Some((node_index, node));
+        })
+    }
+
+    // Adds holders to an extent.  Returns false if the extent isn't one
+    // of the tree's leaves.
+    pub fn hold(&mut self, extent: &Arc<Mutex<Extent>>, nr_holders: usize) -> bool {
+        let begin = extent.lock().unwrap().begin;
+        let (path, leaf_index) = self.path_to(begin);
+        if leaf_index == NULL_NODE {
+            return false;
+        }
+
+        match self.get_mut(leaf_index) {
+            Node::Leaf(leaf) if Arc::ptr_eq(&leaf.extent, extent) => leaf.holders += nr_holders,
+            _ => return false,
+        }
+        for node_index in path {
+            if let Node::Internal(node) = self.get_mut(node_index) {
+                node.holders += nr_holders;
+            }
+        }
+        true
+    }
+
+    // The number of contexts holding an extent, or 0 if it isn't one of
+    // the tree's leaves.
+    pub fn nr_holders_of(&self, extent: &Arc<Mutex<Extent>>) -> usize {
+        let begin = extent.lock().unwrap().begin;
+        let (_, leaf_index) = self.path_to(begin);
+        if leaf_index == NULL_NODE {
+            return 0;
+        }
+
+        match &self.nodes[leaf_index as usize] {
+            Node::Leaf(leaf) if Arc::ptr_eq(&leaf.extent, extent) => leaf.holders,
+            _ => 0,
+        }
+    }
+
+    // The leaf extents, in block order.
+    pub fn extents(&self) -> Vec<Extent> {
+        self.nodes_in_use()
+            .filter_map(|(_, node)| match node {
+                Node::Leaf(leaf) => Some(*leaf.extent.lock().unwrap()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    //--------------------------------
+
+    // Starts, or stops, recording events for a journal.  Starting
+    // records a snapshot of the current extents.
+    pub fn set_journalling(&mut self, enabled: bool) {
+        self.events = None;
+        if enabled {
+            self.events = Some(Vec::new());
+            self.record_snapshot();
+            let bad: Vec<(u64, u64)> = self.bad.iter().collect();
+            for (begin, end) in bad {
+                self.record(Event::Bad { begin, end });
+            }
+        }
+    }
+
+    pub fn take_events(&mut self) -> Vec<Event> {
+        self.events.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    fn record(&mut self, event: Event) {
+        if let Some(events) = &mut self.events {
+            events.push(event);
+        }
+    }
+
+    fn record_snapshot(&mut self) {
+        if self.events.is_some() {
+            let extents = self
+                .extents()
+                .iter()
+                .map(|e| (e.begin, e.end, e.cursor))
+                .collect();
+            self.record(Event::Snapshot {
+                begin: self.begin,
+                end: self.nr_blocks,
+                extents,
+            });
+        }
+    }
+
+    // Records that the cursor of the extent at begin has moved.  Runs of
+    // advances of the same extent are collapsed into one event.
+    pub fn advanced(&mut self, begin: u64, cursor: u64) {
+        if let Some(events) = &mut self.events {
+            if let Some(Event::Advance {
+                begin: b,
+                cursor: c,
+            }) = events.last_mut()
+            {
+                if *b == begin {
+                    *c = cursor;
+                    return;
+                }
+            }
+            events.push(Event::Advance { begin, cursor });
+        }
+    }
+
+    // Replaces the tree with one holding the extents of a journal image.
+    // Free counts are taken from the space map.  Fails with InvalidData,
+    // leaving the tree alone, if the image doesn't fit the tree, eg. the
+    // journal was written with a different class config.
+    pub fn restore<S: SpaceMap + ?Sized>(&mut self, image: &TreeImage, sm: &S) -> io::Result<()> {
+        if image.begin != self.begin {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "journal region starts at {}, not {}",
+                    image.begin, self.begin
+                ),
+            ));
+        }
+        if self.nodes.len() + 1 < 2 * image.extents.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} extents won't fit in {} nodes",
+                    image.extents.len(),
+                    self.nodes.len()
+                ),
+            ));
+        }
+
+        self.free_tree(self.root);
+        self.stale.clear();
+        self.nr_blocks = image.end;
+        for (begin, end) in image.bad.iter() {
+            self.bad.insert(begin, end);
+        }
+
+        let extents: Vec<Extent> = image
+            .extents
+            .iter()
+            .map(|(begin, (end, cursor))| Extent {
+                begin: *begin,
+                end: *end,
+                cursor: *cursor,
+                aligned: *cursor,
+                nr_free: self.count_free(sm, *cursor, *end),
+            })
+            .collect();
+        self.root = self.restore_(&extents);
+        self.record_snapshot();
+        Ok(())
+    }
+
+    fn restore_(&mut self, extents: &[Extent]) -> u8 {
+        if extents.is_empty() {
+            return NULL_NODE;
+        }
+
+        let node_index = self.alloc_node().unwrap();
+        if extents.len() == 1 {
+            self.write_node(
+                node_index,
+                Node::Leaf(Leaf {
+                    extent: Arc::new(Mutex::new(extents[0])),
+                    holders: 0,
+                }),
+            );
+        } else {
+            let mid = extents.len() / 2;
+            let left = self.restore_(&extents[..mid]);
+            let right = self.restore_(&extents[mid..]);
+            self.write_node(
+                node_index,
+                Node::Internal(Internal {
+                    holders: 0,
+                    nr_free_blocks: extents.iter().map(|e| e.nr_free).sum(),
+                    cut: extents[mid].begin,
+                    left,
+                    right,
+                }),
+            );
+        }
+        node_index
+    }
 }
 
 //----------------------------------------------------------------
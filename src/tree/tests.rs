@@ -1,5 +1,6 @@
 use anyhow::{ensure, Result};
 
+use crate::space_map::*;
 use crate::tree::utils::*;
 use crate::tree::*;
 
@@ -68,6 +69,37 @@ fn release_extents_full() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn stale_extents_are_bounded() -> Result<()> {
+    let nr_blocks = 1 << 20;
+    let nr_nodes = 7;
+    let mut tree = Tree::new(nr_blocks, nr_nodes);
+
+    // Keep splitting leaves and using them up, without ever recounting.
+    for _ in 0..1000 {
+        let Some(first) = tree.borrow() else {
+            break;
+        };
+        let second = tree.borrow().unwrap();
+        let shared = Arc::ptr_eq(&first, &second);
+
+        for (extent, full) in [(first, !shared), (second, false)] {
+            {
+                let mut extent = extent.lock().unwrap();
+                extent.cursor = if full {
+                    extent.end
+                } else {
+                    extent.cursor + (extent.end - extent.cursor) / 4
+                };
+            }
+            tree.release(extent);
+        }
+        ensure!(tree.stale.len() <= nr_nodes as usize);
+    }
+
+    Ok(())
+}
+
 #[test]
 fn release_shared_extent() -> Result<()> {
     let nr_blocks = 1024;
@@ -253,4 +285,70 @@ fn remove_shared_extent() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn borrow_avoids_blocked_range() -> Result<()> {
+    let nr_blocks = 1024;
+    let nr_nodes = 3;
+
+    let mut tree = Tree::new(nr_blocks, nr_nodes);
+    tree.block_range(512, nr_blocks);
+
+    let first = tree.borrow().unwrap();
+    let second = tree.borrow().unwrap();
+    check_nr_holders(&tree)?;
+
+    // The right child is blocked, so both borrows share the left one.
+    ensure!(Arc::ptr_eq(&first, &second));
+    ensure!(first.lock().unwrap().end == 512);
+
+    tree.block_range(0, 512);
+    ensure!(tree.borrow().is_none());
+
+    tree.unblock_range(512, nr_blocks);
+    let third = tree.borrow().unwrap();
+    ensure!(third.lock().unwrap().begin == 512);
+
+    Ok(())
+}
+
+#[test]
+fn rebuild_seeds_from_space_map() -> Result<()> {
+    let nr_blocks = 4096;
+    let nr_nodes = 63;
+
+    // The first 70% is full, the rest is sparsely used.
+    let allocated = (0..nr_blocks * 7 / 10).chain((nr_blocks * 7 / 10..nr_blocks).step_by(3));
+    let sm = BitmapSpaceMap::from_allocated(nr_blocks, allocated)?;
+
+    let mut tree = Tree::new(nr_blocks, nr_nodes);
+    tree.rebuild(&sm);
+
+    // Half the nodes are kept back for splitting at run time.
+    ensure!(tree.free_nodes.len() >= nr_nodes as usize / 2);
+
+    let mut stack = vec![tree.root];
+    let mut nr_leaves = 0;
+    while let Some(node_index) = stack.pop() {
+        match tree.read_node(node_index) {
+            Node::Internal(node) => {
+                stack.push(node.left);
+                stack.push(node.right);
+            }
+            Node::Leaf(node) => {
+                let extent = node.extent.lock().unwrap();
+                ensure!(extent.cursor >= nr_blocks * 7 / 10);
+                ensure!(sm.is_free(extent.cursor));
+                nr_leaves += 1;
+            }
+        }
+    }
+    ensure!(nr_leaves > 1);
+
+    let root = tree.read_node(tree.root);
+    ensure!(matches!(root, Node::Internal(_)));
+    ensure!(root.nr_free_blocks() == sm.nr_free());
+
+    Ok(())
+}
+
 //----------------------------------------------------------------
@@ -51,9 +51,9 @@ fn char_run(c: char, fraction: f64, width: usize) -> String {
 pub fn draw_tree(tree: &Tree) {
     let width = 100;
     let mut deque = VecDeque::new();
-    deque.push_back((tree.root, 0, tree.nr_blocks, 0, '-'));
+    deque.push_back((tree.root, tree.begin, tree.nr_blocks, 0, '-'));
 
-    let mut cursor = 0;
+    let mut cursor = tree.begin;
     let mut last_level = None;
     while let Some((node_index, begin, end, level, c)) = deque.pop_front() {
         if node_index == NULL_NODE {
@@ -63,13 +63,13 @@ pub fn draw_tree(tree: &Tree) {
 
         match (last_level, level) {
             (None, level) => {
-                cursor = 0;
+                cursor = tree.begin;
                 last_level = Some(level);
             }
             (Some(last), level) => {
                 if last != level {
                     println!();
-                    cursor = 0;
+                    cursor = tree.begin;
                     last_level = Some(level);
                 }
             }
@@ -79,7 +79,7 @@ pub fn draw_tree(tree: &Tree) {
         if begin > cursor {
             print!(
                 "{} ",
-                char_run(' ', (begin - cursor) as f64 / tree.nr_blocks as f64, width)
+                char_run(' ', (begin - cursor) as f64 / tree.len() as f64, width)
             );
         }
 
@@ -89,7 +89,7 @@ pub fn draw_tree(tree: &Tree) {
                 // Print the node.
                 print!(
                     "{}",
-                    char_run(c, (end - begin) as f64 / tree.nr_blocks as f64, width)
+                    char_run(c, (end - begin) as f64 / tree.len() as f64, width)
                 );
 
                 cursor = end;
@@ -105,7 +105,7 @@ pub fn draw_tree(tree: &Tree) {
                 // Print the node.
                 print!(
                     "{}",
-                    char_run(c, (end - begin) as f64 / tree.nr_blocks as f64, width)
+                    char_run(c, (end - begin) as f64 / tree.len() as f64, width)
                 );
 
                 cursor = end;
@@ -175,8 +175,52 @@ fn checked_holders(n: Node, tree: &Tree) -> Result<usize> {
 }
 
 pub fn check_nr_holders(tree: &Tree) -> Result<()> {
+    if tree.root == NULL_NODE {
+        return Ok(());
+    }
     checked_holders(tree.read_node(tree.root), tree)?;
     Ok(())
 }
 
+fn checked_extents(node_index: u8, begin: u64, end: u64, tree: &Tree) -> Result<()> {
+    if node_index == NULL_NODE {
+        return Ok(());
+    }
+
+    match tree.read_node(node_index) {
+        Node::Internal(n) => {
+            if n.cut < begin || n.cut > end {
+                return Err(anyhow!("cut {} outside {}..{}", n.cut, begin, end));
+            }
+            checked_extents(n.left, begin, n.cut, tree)?;
+            checked_extents(n.right, n.cut, end, tree)?;
+        }
+        Node::Leaf(n) => {
+            let extent = n.extent.lock().unwrap();
+            if extent.begin < begin
+                || extent.end > end
+                || extent.cursor < extent.begin
+                || extent.cursor > extent.end
+            {
+                return Err(anyhow!(
+                    "extent {}..{} (cursor {}) outside {}..{}",
+                    extent.begin,
+                    extent.end,
+                    extent.cursor,
+                    begin,
+                    end
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Checks every leaf lies within the tree's region, on the correct side of
+// the cuts above it, and has its cursor inside the extent.
+pub fn check_extents(tree: &Tree) -> Result<()> {
+    checked_extents(tree.root, tree.begin, tree.nr_blocks, tree)
+}
+
 //----------------------------------------------------------------